@@ -0,0 +1,233 @@
+use crate::lsm6dsm::{IMUData, STANDARD_GRAVITY};
+use embassy_time::Instant;
+use nalgebra::{UnitQuaternion, Vector3};
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq)]
+pub struct AttitudeEstimatorConfig {
+    /// Proportional gain pulling the attitude towards the accelerometer's gravity reference
+    pub kp: f32,
+    /// Integral gain, estimates the remaining gyro bias
+    pub ki: f32,
+    /// The accelerometer is only trusted as a gravity reference while the magnitude of the
+    /// acceleration is within this fraction of 1g, otherwise the gyro is integrated alone
+    pub acc_tolerance: f32,
+}
+
+impl Default for AttitudeEstimatorConfig {
+    fn default() -> Self {
+        Self {
+            kp: 1.0,
+            ki: 0.02,
+            acc_tolerance: 0.1,
+        }
+    }
+}
+
+/// Mahony complementary filter. Samples must be in the vehicle frame (+X towards the nose),
+/// the world frame has +Z pointing up, yaw is relative to the heading at initialization.
+pub struct AttitudeEstimator {
+    config: AttitudeEstimatorConfig,
+    // takes vehicle frame vectors to the world frame
+    orientation: UnitQuaternion<f32>,
+    integral_error: Vector3<f32>,
+    // deg/s, vehicle frame
+    rates: Vector3<f32>,
+    last_timestamp: Option<Instant>,
+    initialized: bool,
+    acc_correction_enabled: bool,
+    gravity_reference_valid: bool,
+}
+
+impl AttitudeEstimator {
+    pub fn new(config: AttitudeEstimatorConfig) -> Self {
+        Self {
+            config,
+            orientation: UnitQuaternion::identity(),
+            integral_error: Vector3::zeros(),
+            rates: Vector3::zeros(),
+            last_timestamp: None,
+            initialized: false,
+            acc_correction_enabled: true,
+            gravity_reference_valid: false,
+        }
+    }
+
+    /// Levels the estimate from a gravity measurement, yaw is set to zero
+    pub fn initialize(&mut self, acc: &[f32; 3]) {
+        let acc = Vector3::from(*acc);
+        self.orientation = UnitQuaternion::rotation_between(&acc, &Vector3::z())
+            // exactly upside down
+            .unwrap_or_else(|| {
+                UnitQuaternion::from_axis_angle(&Vector3::x_axis(), core::f32::consts::PI)
+            });
+        self.integral_error = Vector3::zeros();
+        self.initialized = true;
+    }
+
+    /// Disables the accelerometer correction regardless of the acceleration magnitude,
+    /// e.g. from launch detection until the end of the boost phase
+    pub fn set_acc_correction_enabled(&mut self, enabled: bool) {
+        self.acc_correction_enabled = enabled;
+    }
+
+    /// Uses the sample timestamps to integrate, the first sample only initializes. Samples that
+    /// aren't newer than the previous one are skipped.
+    pub fn update(&mut self, data: &IMUData) {
+        let Some(last_timestamp) = self.last_timestamp else {
+            self.last_timestamp = Some(data.timestamp);
+            if !self.initialized {
+                self.initialize(&data.acc);
+            }
+            return;
+        };
+        // out of order or repeated, keep integrating from the last good sample
+        let Some(dt) = data
+            .timestamp
+            .checked_duration_since(last_timestamp)
+            .filter(|dt| dt.as_ticks() > 0)
+        else {
+            return;
+        };
+        self.last_timestamp = Some(data.timestamp);
+        self.update_with_dt(data, dt.as_micros() as f32 / 1_000_000.0);
+    }
+
+    /// `dt` in seconds since the previous sample
+    pub fn update_with_dt(&mut self, data: &IMUData, dt: f32) {
+        if !self.initialized {
+            self.initialize(&data.acc);
+        }
+
+        self.rates = Vector3::from(data.gyro);
+        let mut gyro = self.rates.map(|rate| rate.to_radians());
+
+        let acc = Vector3::from(data.acc);
+        let acc_norm = acc.norm();
+        self.gravity_reference_valid = self.acc_correction_enabled
+            && (acc_norm - STANDARD_GRAVITY).abs() < self.config.acc_tolerance * STANDARD_GRAVITY;
+        if self.gravity_reference_valid {
+            // world up as seen from the vehicle, compared with the measured direction
+            let estimated_up = self.orientation.inverse_transform_vector(&Vector3::z());
+            let error = (acc / acc_norm).cross(&estimated_up);
+            self.integral_error += error * dt;
+            gyro += error * self.config.kp + self.integral_error * self.config.ki;
+        }
+
+        self.orientation *= UnitQuaternion::from_scaled_axis(gyro * dt);
+        self.orientation.renormalize();
+    }
+
+    pub fn orientation(&self) -> &UnitQuaternion<f32> {
+        &self.orientation
+    }
+
+    /// Angle between the nose and straight up, in degrees
+    pub fn tilt_from_vertical(&self) -> f32 {
+        let nose = self.orientation * Vector3::x();
+        nose.angle(&Vector3::z()).to_degrees()
+    }
+
+    /// Rotation about the nose (+X), deg/s
+    pub fn roll_rate(&self) -> f32 {
+        self.rates.x
+    }
+
+    /// Rotation about +Y, deg/s
+    pub fn pitch_rate(&self) -> f32 {
+        self.rates.y
+    }
+
+    /// Rotation about +Z, deg/s
+    pub fn yaw_rate(&self) -> f32 {
+        self.rates.z
+    }
+
+    /// Whether the last update used the accelerometer, false means gyro-only propagation
+    pub fn gravity_reference_valid(&self) -> bool {
+        self.gravity_reference_valid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orientation::MountingOrientation;
+
+    // board frame sample as the IMU reports it, tilted by `tilt` deg about the board's Z
+    fn sample(tilt: f32, g: f32, gyro: [f32; 3], ms: u64) -> IMUData {
+        let (sin, cos) = tilt.to_radians().sin_cos();
+        let mut data = IMUData {
+            acc: [-cos * g * STANDARD_GRAVITY, sin * g * STANDARD_GRAVITY, 0.0],
+            gyro,
+            timestamp: Instant::from_millis(ms),
+            ..Default::default()
+        };
+        MountingOrientation::NOSE_NEG_X.apply(&mut data);
+        data
+    }
+
+    #[test]
+    fn measures_tilt_from_vertical() {
+        for tilt in [0.0, 60.0] {
+            let mut attitude = AttitudeEstimator::new(AttitudeEstimatorConfig::default());
+            for k in 0..100 {
+                attitude.update(&sample(tilt, 1.0, [0.0; 3], k * 10));
+            }
+            assert!((attitude.tilt_from_vertical() - tilt).abs() < 0.1);
+            assert!(attitude.gravity_reference_valid());
+        }
+    }
+
+    #[test]
+    fn converges_to_the_accelerometer() {
+        let mut attitude = AttitudeEstimator::new(AttitudeEstimatorConfig::default());
+        // levelled lying on its side
+        attitude.initialize(&[0.0, 0.0, STANDARD_GRAVITY]);
+        assert!((attitude.tilt_from_vertical() - 90.0).abs() < 0.1);
+        for k in 0..300 {
+            attitude.update(&sample(0.0, 1.0, [0.0; 3], k * 10));
+        }
+        assert!(attitude.tilt_from_vertical() < 5.0);
+        // the integral term picked up a bias from the initial error and bleeds it off slowly
+        for k in 300..2_000 {
+            attitude.update(&sample(0.0, 1.0, [0.0; 3], k * 10));
+        }
+        assert!(attitude.tilt_from_vertical() < 2.0);
+    }
+
+    #[test]
+    fn integrates_gyro_alone_under_thrust() {
+        let mut attitude = AttitudeEstimator::new(AttitudeEstimatorConfig::default());
+        attitude.update(&sample(0.0, 1.0, [0.0; 3], 0));
+        // 5g along the nose, 10 deg/s about Y for a second
+        for k in 1..=100 {
+            attitude.update(&sample(0.0, 5.0, [0.0, 10.0, 0.0], k * 10));
+            assert!(!attitude.gravity_reference_valid());
+        }
+        assert!((attitude.tilt_from_vertical() - 10.0).abs() < 0.1);
+
+        // 1g that disagrees with the attitude is ignored while the correction is off
+        attitude.set_acc_correction_enabled(false);
+        for k in 101..=200 {
+            attitude.update(&sample(40.0, 1.0, [0.0; 3], k * 10));
+            assert!(!attitude.gravity_reference_valid());
+        }
+        assert!((attitude.tilt_from_vertical() - 10.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn skips_samples_out_of_order() {
+        let mut attitude = AttitudeEstimator::new(AttitudeEstimatorConfig::default());
+        attitude.set_acc_correction_enabled(false);
+        attitude.update(&sample(0.0, 1.0, [0.0; 3], 1_000));
+        attitude.update(&sample(0.0, 1.0, [0.0, 10.0, 0.0], 2_000));
+        assert!((attitude.tilt_from_vertical() - 10.0).abs() < 0.1);
+        // older and repeated timestamps change nothing
+        attitude.update(&sample(0.0, 1.0, [0.0, 1_000.0, 0.0], 1_500));
+        attitude.update(&sample(0.0, 1.0, [0.0, 1_000.0, 0.0], 2_000));
+        assert!((attitude.tilt_from_vertical() - 10.0).abs() < 0.1);
+        // and the next sample integrates from the last good one
+        attitude.update(&sample(0.0, 1.0, [0.0, 10.0, 0.0], 3_000));
+        assert!((attitude.tilt_from_vertical() - 20.0).abs() < 0.1);
+    }
+}
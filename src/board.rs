@@ -0,0 +1,275 @@
+use crate::clock::{clock_config, log_frequencies, verify_revision};
+use crate::clock_tree::ClockProfile;
+use crate::gps_config::SetBaudRate;
+use crate::pps_capture::CapturedEdge;
+use crate::revision::RevisionDetection;
+use crate::time_discipline::instant_ns;
+use embassy_stm32::exti::ExtiInput;
+use embassy_stm32::gpio::{AnyPin, Pull};
+use embassy_stm32::peripherals::{DMA1_CH4, DMA1_CH5, EXTI3, PC10, PC11, PC12, PE3, SPI3};
+#[cfg(feature = "vlf4r1")]
+use embassy_stm32::peripherals::{EXTI5, PA0, PA1, PB5, TIM3, UART4};
+#[cfg(feature = "vlf4r2")]
+use embassy_stm32::peripherals::{EXTI12, PA2, PA3, PD12, TIM4, USART2};
+use embassy_stm32::time::Hertz;
+use embassy_stm32::timer::input_capture::{CapturePin, InputCapture};
+use embassy_stm32::timer::{self, Channel, low_level::CountingMode};
+use embassy_stm32::usart::{self, BufferedUart, Config as UartConfig};
+use embassy_stm32::{Peri, bind_interrupts, pac};
+use embassy_time::Instant;
+
+#[cfg(all(feature = "vlf4r1", feature = "vlf4r2"))]
+compile_error!(
+    "features \"vlf4r1\" and \"vlf4r2\" both select the board revision, enable only one"
+);
+#[cfg(not(any(feature = "vlf4r1", feature = "vlf4r2")))]
+compile_error!("select the board revision with feature \"vlf4r1\" or \"vlf4r2\"");
+
+// pin map, the only place that knows about the board revision
+#[cfg(feature = "vlf4r1")]
+mod pins {
+    use super::*;
+
+    pub type GpsUart = UART4;
+    pub type GpsRx = PA1;
+    pub type GpsTx = PA0;
+    pub type PpsPin = PB5;
+    pub type PpsExti = EXTI5;
+    /// PB5 is TIM3_CH2
+    pub type PpsTimer = TIM3;
+    pub const PPS_CHANNEL: Channel = Channel::Ch2;
+    pub const PPS_TIMER_REGS: pac::timer::TimGp16 = pac::TIM3;
+
+    bind_interrupts!(pub struct GpsIrqs {
+        UART4 => usart::BufferedInterruptHandler<UART4>;
+    });
+    bind_interrupts!(pub struct PpsIrqs {
+        TIM3 => timer::CaptureCompareInterruptHandler<TIM3>;
+    });
+
+    pub fn pps_capture(
+        pin: Peri<'static, PpsPin>,
+        timer: Peri<'static, PpsTimer>,
+    ) -> InputCapture<'static, PpsTimer> {
+        let pin = CapturePin::new(pin, Pull::None);
+        InputCapture::new(
+            timer,
+            None,
+            Some(pin),
+            None,
+            None,
+            PpsIrqs,
+            PPS_CAPTURE_FREQUENCY,
+            CountingMode::EdgeAlignedUp,
+        )
+    }
+}
+
+#[cfg(feature = "vlf4r2")]
+mod pins {
+    use super::*;
+
+    pub type GpsUart = USART2;
+    pub type GpsRx = PA3;
+    pub type GpsTx = PA2;
+    pub type PpsPin = PD12;
+    pub type PpsExti = EXTI12;
+    /// PD12 is TIM4_CH1
+    pub type PpsTimer = TIM4;
+    pub const PPS_CHANNEL: Channel = Channel::Ch1;
+    pub const PPS_TIMER_REGS: pac::timer::TimGp16 = pac::TIM4;
+
+    bind_interrupts!(pub struct GpsIrqs {
+        USART2 => usart::BufferedInterruptHandler<USART2>;
+    });
+    bind_interrupts!(pub struct PpsIrqs {
+        TIM4 => timer::CaptureCompareInterruptHandler<TIM4>;
+    });
+
+    pub fn pps_capture(
+        pin: Peri<'static, PpsPin>,
+        timer: Peri<'static, PpsTimer>,
+    ) -> InputCapture<'static, PpsTimer> {
+        let pin = CapturePin::new(pin, Pull::None);
+        InputCapture::new(
+            timer,
+            Some(pin),
+            None,
+            None,
+            None,
+            PpsIrqs,
+            PPS_CAPTURE_FREQUENCY,
+            CountingMode::EdgeAlignedUp,
+        )
+    }
+}
+
+use pins::*;
+
+/// Tick rate of the PPS capture timer, divides the timer clock of every clock profile
+// CaptureTimeline converts captures to Instant by assuming this and the time driver's timer
+// (TIM2, chosen in Cargo.toml) tick from the same timer clock
+pub const PPS_CAPTURE_FREQUENCY: Hertz = Hertz(16_000_000);
+/// Width of the PPS capture timer's counter
+pub const PPS_CAPTURE_BITS: u32 = 16;
+
+/// The board's peripherals, split into named resources that are the same on every revision
+pub struct Board {
+    pub revision: RevisionDetection,
+    /// Red led, high -> led on
+    pub status_led: Peri<'static, AnyPin>,
+    pub imu: ImuBus,
+    pub imu_int1: ImuInterrupt,
+    pub gps: GpsPort,
+    pub pps: PpsInput,
+}
+
+impl Board {
+    /// Initializes the chip with the `Performance` clock profile and checks that the board
+    /// matches the `vlf4r1` / `vlf4r2` feature. Change the default feature in Cargo.toml.
+    pub fn init() -> Self {
+        Self::init_with_clock(ClockProfile::Performance)
+    }
+
+    pub fn init_with_clock(profile: ClockProfile) -> Self {
+        let p = embassy_stm32::init(clock_config(&profile.tree()));
+        log_frequencies(profile);
+        let revision = verify_revision(p.ADC1, p.PC4);
+
+        #[cfg(feature = "vlf4r1")]
+        let (status_led, imu_cs, gps, pps) = (
+            p.PB9.into(),
+            p.PA15.into(),
+            GpsPort {
+                uart: p.UART4,
+                rx: p.PA1,
+                tx: p.PA0,
+            },
+            PpsInput {
+                pin: p.PB5,
+                exti: p.EXTI5,
+                timer: p.TIM3,
+            },
+        );
+        #[cfg(feature = "vlf4r2")]
+        let (status_led, imu_cs, gps, pps) = (
+            p.PD10.into(),
+            p.PC13.into(),
+            GpsPort {
+                uart: p.USART2,
+                rx: p.PA3,
+                tx: p.PA2,
+            },
+            PpsInput {
+                pin: p.PD12,
+                exti: p.EXTI12,
+                timer: p.TIM4,
+            },
+        );
+
+        Self {
+            revision,
+            status_led,
+            imu: ImuBus {
+                spi: p.SPI3,
+                sck: p.PC10,
+                mosi: p.PC12,
+                miso: p.PC11,
+                cs: imu_cs,
+                tx_dma: p.DMA1_CH4,
+                rx_dma: p.DMA1_CH5,
+            },
+            imu_int1: ImuInterrupt {
+                pin: p.PE3,
+                exti: p.EXTI3,
+            },
+            gps,
+            pps,
+        }
+    }
+}
+
+/// SPI bus of the LSM6DSM
+pub struct ImuBus {
+    pub spi: Peri<'static, SPI3>,
+    pub sck: Peri<'static, PC10>,
+    pub mosi: Peri<'static, PC12>,
+    pub miso: Peri<'static, PC11>,
+    pub cs: Peri<'static, AnyPin>,
+    pub tx_dma: Peri<'static, DMA1_CH4>,
+    pub rx_dma: Peri<'static, DMA1_CH5>,
+}
+
+/// LSM6DSM INT1
+pub struct ImuInterrupt {
+    pin: Peri<'static, PE3>,
+    exti: Peri<'static, EXTI3>,
+}
+
+impl ImuInterrupt {
+    pub fn into_input(self) -> ExtiInput<'static> {
+        ExtiInput::new(self.pin, self.exti, Pull::None)
+    }
+}
+
+/// UART connected to the GPS receiver
+pub struct GpsPort {
+    uart: Peri<'static, GpsUart>,
+    rx: Peri<'static, GpsRx>,
+    tx: Peri<'static, GpsTx>,
+}
+
+impl GpsPort {
+    pub fn into_buffered_uart(
+        self,
+        tx_buf: &'static mut [u8],
+        rx_buf: &'static mut [u8],
+        config: UartConfig,
+    ) -> Result<BufferedUart<'static>, usart::ConfigError> {
+        BufferedUart::new(self.uart, self.rx, self.tx, tx_buf, rx_buf, GpsIrqs, config)
+    }
+}
+
+impl SetBaudRate for BufferedUart<'static> {
+    fn set_baud_rate(&mut self, baud_rate: u32) -> bool {
+        self.set_baudrate(baud_rate).is_ok()
+    }
+}
+
+/// GPS pulse per second output, wired to a timer channel on every revision
+pub struct PpsInput {
+    pin: Peri<'static, PpsPin>,
+    exti: Peri<'static, PpsExti>,
+    timer: Peri<'static, PpsTimer>,
+}
+
+impl PpsInput {
+    pub fn into_input(self) -> ExtiInput<'static> {
+        ExtiInput::new(self.pin, self.exti, Pull::None)
+    }
+
+    /// Timestamps the edges with the timer's input capture instead of the EXTI interrupt
+    pub fn into_capture(self) -> PpsCapture {
+        PpsCapture {
+            capture: pps_capture(self.pin, self.timer),
+        }
+    }
+}
+
+pub struct PpsCapture {
+    capture: InputCapture<'static, PpsTimer>,
+}
+
+impl PpsCapture {
+    /// Waits for a rising edge. Convert the result with a `CaptureTimeline` created from
+    /// `PPS_CAPTURE_FREQUENCY` and `PPS_CAPTURE_BITS`.
+    pub async fn wait_for_edge(&mut self) -> CapturedEdge {
+        let capture = self.capture.wait_for_rising_edge(PPS_CHANNEL).await;
+        // counter and time read together
+        cortex_m::interrupt::free(|_| CapturedEdge {
+            capture,
+            counter: PPS_TIMER_REGS.cnt().read().cnt() as u32,
+            now_ns: instant_ns(Instant::now()),
+        })
+    }
+}
@@ -0,0 +1,350 @@
+use crate::lsm6dsm::{IMUData, STANDARD_GRAVITY};
+use nalgebra::{Matrix3, Matrix4, Matrix4x3, Vector3, Vector4};
+
+/// Correction applied to raw IMU samples: `acc = acc_matrix * raw_acc + acc_offset` and
+/// `gyro = raw_gyro - gyro_bias`. A diagonal `acc_matrix` only corrects scale, off-diagonal
+/// terms correct cross-axis misalignment.
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq)]
+pub struct ImuCalibration {
+    /// Row major
+    pub acc_matrix: [[f32; 3]; 3],
+    pub acc_offset: [f32; 3], // m/s^2
+    pub gyro_bias: [f32; 3],  // deg/s
+}
+
+impl ImuCalibration {
+    pub const IDENTITY: Self = Self {
+        acc_matrix: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+        acc_offset: [0.0; 3],
+        gyro_bias: [0.0; 3],
+    };
+
+    pub fn apply(&self, data: &mut IMUData) {
+        let acc = Vector3::from(data.acc);
+        let acc_matrix = Matrix3::from_fn(|row, column| self.acc_matrix[row][column]);
+        data.acc = (acc_matrix * acc + Vector3::from(self.acc_offset)).into();
+        data.gyro = (Vector3::from(data.gyro) - Vector3::from(self.gyro_bias)).into();
+    }
+}
+
+impl Default for ImuCalibration {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+/// Which board axis points up during one of the six calibration positions
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalibrationPosition {
+    XUp,
+    XDown,
+    YUp,
+    YDown,
+    ZUp,
+    ZDown,
+}
+
+impl CalibrationPosition {
+    pub const ALL: [Self; 6] = [
+        Self::XUp,
+        Self::XDown,
+        Self::YUp,
+        Self::YDown,
+        Self::ZUp,
+        Self::ZDown,
+    ];
+
+    /// What an ideal accelerometer reads at rest in this position
+    pub fn expected_acc(&self) -> Vector3<f32> {
+        let g = STANDARD_GRAVITY;
+        match self {
+            Self::XUp => Vector3::new(g, 0.0, 0.0),
+            Self::XDown => Vector3::new(-g, 0.0, 0.0),
+            Self::YUp => Vector3::new(0.0, g, 0.0),
+            Self::YDown => Vector3::new(0.0, -g, 0.0),
+            Self::ZUp => Vector3::new(0.0, 0.0, g),
+            Self::ZDown => Vector3::new(0.0, 0.0, -g),
+        }
+    }
+
+    /// The position whose expected reading is closest to `acc`, if `acc` is within
+    /// `tolerance` (m/s^2) of it
+    pub fn detect(acc: &[f32; 3], tolerance: f32) -> Option<Self> {
+        let acc = Vector3::from(*acc);
+        Self::ALL
+            .into_iter()
+            .map(|position| (position, (acc - position.expected_acc()).norm()))
+            .min_by(|(_, a), (_, b)| a.total_cmp(b))
+            .filter(|(_, error)| *error < tolerance)
+            .map(|(position, _)| position)
+    }
+
+    fn index(&self) -> usize {
+        match self {
+            Self::XUp => 0,
+            Self::XDown => 1,
+            Self::YUp => 2,
+            Self::YDown => 3,
+            Self::ZUp => 4,
+            Self::ZDown => 5,
+        }
+    }
+}
+
+/// Running mean and variance of a 3 axis signal (Welford's algorithm)
+#[derive(Debug, Clone, Copy, Default)]
+pub struct AxisStats {
+    count: u32,
+    mean: [f64; 3],
+    m2: [f64; 3],
+}
+
+impl AxisStats {
+    pub fn add(&mut self, sample: &[f32; 3]) {
+        self.count += 1;
+        for (axis, &value) in sample.iter().enumerate() {
+            let value = value as f64;
+            let delta = value - self.mean[axis];
+            self.mean[axis] += delta / self.count as f64;
+            self.m2[axis] += delta * (value - self.mean[axis]);
+        }
+    }
+
+    pub fn count(&self) -> u32 {
+        self.count
+    }
+
+    pub fn mean(&self) -> [f32; 3] {
+        self.mean.map(|mean| mean as f32)
+    }
+
+    /// Largest variance of the three axes
+    pub fn max_variance(&self) -> f32 {
+        if self.count < 2 {
+            return 0.0;
+        }
+        self.m2
+            .iter()
+            .map(|m2| m2 / (self.count - 1) as f64)
+            .fold(0.0, f64::max) as f32
+    }
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalibrationError {
+    /// Not enough samples for this position
+    MissingPosition(CalibrationPosition),
+    /// The board moved while samples were taken
+    NotStationary(CalibrationPosition),
+    /// The positions don't constrain the solution, e.g. the same face was up twice
+    Degenerate,
+}
+
+/// Collects samples in the six positions and solves for an `ImuCalibration`.
+/// The gyro bias is estimated from all samples since the board is stationary in each position.
+#[derive(Debug, Clone, Default)]
+pub struct SixPositionCalibrator {
+    acc: [AxisStats; 6],
+    gyro: AxisStats,
+    /// Minimum samples per position
+    pub min_samples: u32,
+    /// Maximum accelerometer standard deviation per position, m/s^2
+    pub max_acc_std_dev: f32,
+}
+
+impl SixPositionCalibrator {
+    pub fn new() -> Self {
+        Self {
+            min_samples: 200,
+            max_acc_std_dev: 0.2,
+            ..Default::default()
+        }
+    }
+
+    /// `data` should be uncalibrated, with any temperature compensation already applied
+    pub fn add(&mut self, position: CalibrationPosition, data: &IMUData) {
+        self.acc[position.index()].add(&data.acc);
+        self.gyro.add(&data.gyro);
+    }
+
+    pub fn samples(&self, position: CalibrationPosition) -> u32 {
+        self.acc[position.index()].count()
+    }
+
+    /// Discards the samples taken in `position`, e.g. after the board was bumped
+    pub fn clear(&mut self, position: CalibrationPosition) {
+        self.acc[position.index()] = AxisStats::default();
+    }
+
+    /// Per axis offset and scale, plus cross-axis misalignment if `fit_misalignment` is set
+    pub fn solve(&self, fit_misalignment: bool) -> Result<ImuCalibration, CalibrationError> {
+        for position in CalibrationPosition::ALL {
+            let stats = &self.acc[position.index()];
+            if stats.count() < self.min_samples {
+                return Err(CalibrationError::MissingPosition(position));
+            }
+            if stats.max_variance() > self.max_acc_std_dev * self.max_acc_std_dev {
+                return Err(CalibrationError::NotStationary(position));
+            }
+        }
+
+        let (acc_matrix, acc_offset) = if fit_misalignment {
+            self.solve_affine()?
+        } else {
+            self.solve_scale_offset()?
+        };
+
+        Ok(ImuCalibration {
+            acc_matrix: [0, 1, 2].map(|row| [0, 1, 2].map(|column| acc_matrix[(row, column)])),
+            acc_offset: acc_offset.into(),
+            gyro_bias: self.gyro.mean(),
+        })
+    }
+
+    fn mean(&self, position: CalibrationPosition) -> Vector3<f32> {
+        Vector3::from(self.acc[position.index()].mean())
+    }
+
+    // each axis only from its own up / down pair
+    fn solve_scale_offset(&self) -> Result<(Matrix3<f32>, Vector3<f32>), CalibrationError> {
+        let pairs = [
+            (CalibrationPosition::XUp, CalibrationPosition::XDown),
+            (CalibrationPosition::YUp, CalibrationPosition::YDown),
+            (CalibrationPosition::ZUp, CalibrationPosition::ZDown),
+        ];
+        let mut matrix = Matrix3::zeros();
+        let mut offset = Vector3::zeros();
+        for (axis, (up, down)) in pairs.into_iter().enumerate() {
+            let up = self.mean(up)[axis];
+            let down = self.mean(down)[axis];
+            if up - down < STANDARD_GRAVITY {
+                return Err(CalibrationError::Degenerate);
+            }
+            let scale = 2.0 * STANDARD_GRAVITY / (up - down);
+            matrix[(axis, axis)] = scale;
+            offset[axis] = -scale * (up + down) / 2.0;
+        }
+        Ok((matrix, offset))
+    }
+
+    // least squares fit of `expected = matrix * measured + offset` over all six positions
+    fn solve_affine(&self) -> Result<(Matrix3<f32>, Vector3<f32>), CalibrationError> {
+        let mut normal = Matrix4::<f32>::zeros();
+        let mut rhs = Matrix4x3::<f32>::zeros();
+        for position in CalibrationPosition::ALL {
+            let measured = self.mean(position);
+            let row = Vector4::new(measured.x, measured.y, measured.z, 1.0);
+            normal += row * row.transpose();
+            rhs += row * position.expected_acc().transpose();
+        }
+        let solution = normal.try_inverse().ok_or(CalibrationError::Degenerate)? * rhs;
+
+        let matrix = solution.fixed_view::<3, 3>(0, 0).transpose();
+        let offset = solution.fixed_view::<1, 3>(3, 0).transpose();
+        if matrix.iter().chain(offset.iter()).any(|v| !v.is_finite()) {
+            return Err(CalibrationError::Degenerate);
+        }
+        Ok((matrix, offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GYRO_BIAS: [f32; 3] = [0.5, -0.3, 0.1];
+
+    // sensor reading `raw = sensitivity * true + bias` with ±0.05 m/s^2 of noise
+    fn calibrator(sensitivity: Matrix3<f32>, bias: Vector3<f32>) -> SixPositionCalibrator {
+        let mut calibrator = SixPositionCalibrator::new();
+        for position in CalibrationPosition::ALL {
+            let raw = sensitivity * position.expected_acc() + bias;
+            for k in 0..calibrator.min_samples {
+                let noise = if k.is_multiple_of(2) { 0.05 } else { -0.05 };
+                let data = IMUData {
+                    acc: raw.add_scalar(noise).into(),
+                    gyro: GYRO_BIAS.map(|bias| bias + noise),
+                    ..Default::default()
+                };
+                calibrator.add(position, &data);
+            }
+        }
+        calibrator
+    }
+
+    fn assert_recovers(
+        calibration: &ImuCalibration,
+        sensitivity: Matrix3<f32>,
+        bias: Vector3<f32>,
+        tolerance: f32,
+    ) {
+        let matrix = Matrix3::from_fn(|row, column| calibration.acc_matrix[row][column]);
+        let expected_matrix = sensitivity.try_inverse().unwrap();
+        let expected_offset = -expected_matrix * bias;
+        assert!((matrix - expected_matrix).abs().max() < tolerance);
+        assert!(
+            (Vector3::from(calibration.acc_offset) - expected_offset)
+                .abs()
+                .max()
+                < tolerance
+        );
+        for (bias, expected) in calibration.gyro_bias.iter().zip(GYRO_BIAS) {
+            assert!((bias - expected).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn recovers_bias_scale_and_misalignment() {
+        let bias = Vector3::new(0.3, -0.2, 0.45);
+        // scale only
+        let sensitivity = Matrix3::from_diagonal(&Vector3::new(1.02, 0.97, 1.05));
+        let calibration = calibrator(sensitivity, bias).solve(false).unwrap();
+        assert_recovers(&calibration, sensitivity, bias, 1e-3);
+
+        // cross-axis terms, only the affine fit gets them
+        let sensitivity = Matrix3::new(1.02, 0.01, -0.02, 0.015, 0.97, 0.005, -0.01, 0.02, 1.05);
+        let calibration = calibrator(sensitivity, bias).solve(true).unwrap();
+        assert_recovers(&calibration, sensitivity, bias, 1e-3);
+        let calibration = calibrator(sensitivity, bias).solve(false).unwrap();
+        assert_eq!(calibration.acc_matrix[0][1], 0.0);
+
+        // calibrated readings are back on the expected values
+        let mut data = IMUData {
+            acc: (sensitivity * CalibrationPosition::YDown.expected_acc() + bias).into(),
+            ..Default::default()
+        };
+        calibrator(sensitivity, bias)
+            .solve(true)
+            .unwrap()
+            .apply(&mut data);
+        let error = Vector3::from(data.acc) - CalibrationPosition::YDown.expected_acc();
+        assert!(error.norm() < 1e-3);
+    }
+
+    #[test]
+    fn rejects_incomplete_and_moving_positions() {
+        let sensitivity = Matrix3::identity();
+        let mut calibrator = calibrator(sensitivity, Vector3::zeros());
+        calibrator.clear(CalibrationPosition::ZDown);
+        assert_eq!(
+            calibrator.solve(true),
+            Err(CalibrationError::MissingPosition(
+                CalibrationPosition::ZDown
+            ))
+        );
+
+        // picked up while sampling
+        for k in 0..calibrator.min_samples {
+            let swing = if k.is_multiple_of(2) { 1.0 } else { -1.0 };
+            let data = IMUData {
+                acc: [swing, 0.0, -STANDARD_GRAVITY],
+                ..Default::default()
+            };
+            calibrator.add(CalibrationPosition::ZDown, &data);
+        }
+        assert_eq!(
+            calibrator.solve(false),
+            Err(CalibrationError::NotStationary(CalibrationPosition::ZDown))
+        );
+    }
+}
@@ -1,56 +1,67 @@
+use crate::clock_tree::{self, ClockProfile, ClockTree, HSE_FREQUENCY};
+use crate::revision::{BoardRevision, RevisionDetection};
 use embassy_stm32::{Config, Peri};
 use embassy_stm32::adc::{Adc, AdcChannel, SampleTime};
 use embassy_stm32::peripherals::{ADC1, PC4};
 use embassy_stm32::rcc::mux::*;
 use embassy_stm32::rcc::*;
+use embassy_stm32::time::Hertz;
 
 pub fn vlf4_clock() -> Config {
+    clock_config(&ClockProfile::Performance.tree())
+}
+
+/// Panics if the tree breaks the limits of the chip, see `ClockTree::validate`
+pub fn clock_config(tree: &ClockTree) -> Config {
+    if let Err(e) = tree.validate() {
+        defmt::panic!("Invalid clock tree: {}", e);
+    }
+
     let mut config = Config::default();
-    config.rcc.hsi = Some(HSIPrescaler::DIV4);
-    config.rcc.hse = None;
+    match tree.pll_source {
+        clock_tree::PllSource::Hsi => {
+            config.rcc.hsi = Some(hsi_prescaler(tree.hsi_div));
+            config.rcc.hse = None;
+        }
+        clock_tree::PllSource::Hse => {
+            config.rcc.hsi = None;
+            config.rcc.hse = Some(Hse {
+                freq: Hertz(HSE_FREQUENCY),
+                mode: HseMode::Oscillator,
+            });
+        }
+    }
     config.rcc.csi = false;
     config.rcc.hsi48 = Some(Hsi48Config {
         sync_from_usb: false,
     });
     config.rcc.ls = LsConfig::default_lsi();
 
-    config.rcc.pll1 = Some(Pll {
-        source: PllSource::HSI,
-        prediv: PllPreDiv::DIV1,
-        mul: PllMul::MUL32,
-        divp: Some(PllDiv::DIV1),
-        divq: Some(PllDiv::DIV4),
-        divr: Some(PllDiv::DIV2),
-    });
-
-    config.rcc.pll2 = Some(Pll {
-        source: PllSource::HSI,
-        prediv: PllPreDiv::DIV1,
-        mul: PllMul::MUL20,
-        divp: Some(PllDiv::DIV8),
-        divq: Some(PllDiv::DIV2),
-        divr: Some(PllDiv::DIV2),
-    });
-    config.rcc.pll3 = Some(Pll {
-        source: PllSource::HSI,
-        prediv: PllPreDiv::DIV1,
-        mul: PllMul::MUL24,
-        divp: Some(PllDiv::DIV2),
-        divq: Some(PllDiv::DIV8),
-        divr: Some(PllDiv::DIV2),
-    });
+    let source = match tree.pll_source {
+        clock_tree::PllSource::Hsi => PllSource::HSI,
+        clock_tree::PllSource::Hse => PllSource::HSE,
+    };
+    config.rcc.pll1 = Some(pll(source, &tree.pll1));
+    config.rcc.pll2 = Some(pll(source, &tree.pll2));
+    config.rcc.pll3 = Some(pll(source, &tree.pll3));
 
     config.rcc.sys = Sysclk::PLL1_P;
-    config.rcc.d1c_pre = AHBPrescaler::DIV1;
+    config.rcc.d1c_pre = ahb_prescaler(tree.d1c_pre);
 
-    config.rcc.apb1_pre = APBPrescaler::DIV2;
-    config.rcc.apb2_pre = APBPrescaler::DIV2;
-    config.rcc.apb3_pre = APBPrescaler::DIV2;
-    config.rcc.apb4_pre = APBPrescaler::DIV2;
-    config.rcc.ahb_pre = AHBPrescaler::DIV2;
+    config.rcc.apb1_pre = apb_prescaler(tree.apb1_pre);
+    config.rcc.apb2_pre = apb_prescaler(tree.apb2_pre);
+    config.rcc.apb3_pre = apb_prescaler(tree.apb3_pre);
+    config.rcc.apb4_pre = apb_prescaler(tree.apb4_pre);
+    config.rcc.ahb_pre = ahb_prescaler(tree.ahb_pre);
 
-    config.rcc.voltage_scale = VoltageScale::Scale0;
+    config.rcc.voltage_scale = match tree.voltage_scale {
+        clock_tree::VoltageScale::Scale0 => VoltageScale::Scale0,
+        clock_tree::VoltageScale::Scale1 => VoltageScale::Scale1,
+        clock_tree::VoltageScale::Scale2 => VoltageScale::Scale2,
+        clock_tree::VoltageScale::Scale3 => VoltageScale::Scale3,
+    };
 
+    // keep in sync with the muxing described in clock_tree.rs
     config.rcc.mux.spi123sel = Saisel::PLL1_Q;
     config.rcc.mux.usart234578sel = Usart234578sel::PCLK1;
     config.rcc.mux.rngsel = Rngsel::HSI48;
@@ -65,23 +76,106 @@ pub fn vlf4_clock() -> Config {
     config
 }
 
-pub fn verify_revision(adc1: Peri<'_, ADC1>, pc4: Peri<'_, PC4>) {
-    // On r1, PC4 is connected to curr_ref so it should be 2.5V
-    // On r2, PC4 is connected to green led, so it should be 0V
+/// Logs the frequencies of a clock profile
+pub fn log_frequencies(profile: ClockProfile) {
+    let f = profile.frequencies();
+    defmt::info!(
+        "{} clock: SYSCLK {} Hz, HCLK {} Hz, PCLK1 {} Hz, PCLK2 {} Hz, PCLK3 {} Hz, PCLK4 {} Hz",
+        profile,
+        f.sysclk,
+        f.hclk,
+        f.pclk1,
+        f.pclk2,
+        f.pclk3,
+        f.pclk4
+    );
+    defmt::info!(
+        "Kernel clocks: SPI123 {} Hz, ADC {} Hz, FDCAN {} Hz, USB {} Hz, USART {} Hz",
+        f.spi123,
+        f.adc,
+        f.fdcan,
+        f.usb,
+        f.usart234578
+    );
+}
+
+fn pll(source: PllSource, pll: &clock_tree::Pll) -> Pll {
+    // register values: DIVM is the divider, DIVN and DIVx are one less
+    Pll {
+        source,
+        prediv: PllPreDiv::from_bits(pll.prediv as u8),
+        mul: PllMul::from_bits((pll.mul - 1) as u16),
+        divp: pll.divp.map(|div| PllDiv::from_bits((div - 1) as u8)),
+        divq: pll.divq.map(|div| PllDiv::from_bits((div - 1) as u8)),
+        divr: pll.divr.map(|div| PllDiv::from_bits((div - 1) as u8)),
+    }
+}
+
+fn hsi_prescaler(div: u32) -> HSIPrescaler {
+    match div {
+        1 => HSIPrescaler::DIV1,
+        2 => HSIPrescaler::DIV2,
+        4 => HSIPrescaler::DIV4,
+        8 => HSIPrescaler::DIV8,
+        _ => defmt::panic!("Invalid HSI prescaler {}", div),
+    }
+}
+
+fn ahb_prescaler(div: u32) -> AHBPrescaler {
+    match div {
+        1 => AHBPrescaler::DIV1,
+        2 => AHBPrescaler::DIV2,
+        4 => AHBPrescaler::DIV4,
+        8 => AHBPrescaler::DIV8,
+        16 => AHBPrescaler::DIV16,
+        64 => AHBPrescaler::DIV64,
+        128 => AHBPrescaler::DIV128,
+        256 => AHBPrescaler::DIV256,
+        512 => AHBPrescaler::DIV512,
+        _ => defmt::panic!("Invalid AHB prescaler {}", div),
+    }
+}
+
+fn apb_prescaler(div: u32) -> APBPrescaler {
+    match div {
+        1 => APBPrescaler::DIV1,
+        2 => APBPrescaler::DIV2,
+        4 => APBPrescaler::DIV4,
+        8 => APBPrescaler::DIV8,
+        16 => APBPrescaler::DIV16,
+        _ => defmt::panic!("Invalid APB prescaler {}", div),
+    }
+}
 
+/// Reads PC4 to tell r1 and r2 apart
+pub fn detect_revision(adc1: Peri<'_, ADC1>, pc4: Peri<'_, PC4>) -> RevisionDetection {
     let mut adc = Adc::new(adc1);
     adc.set_sample_time(SampleTime::CYCLES387_5);
     let raw_value = adc.blocking_read(&mut pc4.degrade_adc());
+    RevisionDetection::from_raw(raw_value)
+}
 
-    if raw_value > 20000 {
-        // is r1
-        if cfg!(feature = "vlf4r2") {
-            defmt::panic!("\"vlf4r2\" feature is selected but running on r1")
-        }
-    }else {
-        // is r2
-        if cfg!(feature = "vlf4r1") {
-            defmt::panic!("\"vlf4r1\" feature is selected but running on r2")
-        }
+/// Panics if the board is not the revision selected with the `vlf4r1` / `vlf4r2` feature.
+/// An ambiguous reading only logs a warning and the feature is trusted.
+pub fn verify_revision(adc1: Peri<'_, ADC1>, pc4: Peri<'_, PC4>) -> RevisionDetection {
+    let detection = detect_revision(adc1, pc4);
+    let compiled = BoardRevision::compiled();
+    if detection.conflicts_with(compiled) {
+        defmt::panic!(
+            "{} is selected but running on {} (PC4 reading {})",
+            compiled,
+            detection.revision,
+            detection.raw
+        );
+    }
+    if detection.revision == BoardRevision::Unknown {
+        defmt::warn!(
+            "Could not detect the board revision (PC4 reading {}), assuming {}",
+            detection.raw,
+            compiled
+        );
+    } else {
+        defmt::info!("Detected board revision {}", detection);
     }
+    detection
 }
@@ -0,0 +1,455 @@
+// Clock profiles as plain numbers so the resulting frequencies can be computed and checked
+// against the chip's limits on the host. The built-in profiles are also checked at compile time.
+// `clock::clock_config` turns a `ClockTree` into the embassy rcc config.
+//
+// Every profile uses the same kernel clock muxing:
+//   SYSCLK      <- PLL1 P
+//   SPI1/2/3    <- PLL1 Q
+//   FDCAN       <- PLL1 Q
+//   ADC         <- PLL2 P
+//   USB         <- PLL3 Q
+//   USART2..8   <- PCLK1
+//   I2C1/2/3/5  <- PCLK1
+//   SPI4/5      <- PCLK2
+//   SPI6, I2C4  <- PCLK4
+//   RNG         <- HSI48
+
+pub const HSI_FREQUENCY: u32 = 64_000_000;
+pub const HSI48_FREQUENCY: u32 = 48_000_000;
+/// Crystal used by `ClockProfile::Hse`, the PLLs expect a multiple of 2MHz
+pub const HSE_FREQUENCY: u32 = 16_000_000;
+
+// STM32H723 limits
+const PLL_INPUT_MIN: u32 = 1_000_000;
+const PLL_INPUT_MAX: u32 = 16_000_000;
+// VCOL below 2MHz input, VCOH above
+const VCO_WIDE_INPUT_MIN: u32 = 2_000_000;
+const VCOL_RANGE: (u32, u32) = (150_000_000, 420_000_000);
+const VCOH_RANGE: (u32, u32) = (192_000_000, 836_000_000);
+const USB_FREQUENCY: u32 = 48_000_000;
+const ADC_MAX: u32 = 50_000_000;
+const FDCAN_MAX: u32 = 150_000_000;
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockProfile {
+    /// 512MHz core from the HSI at VoltageScale0
+    Performance,
+    /// 80MHz core at VoltageScale3, for long waits on the pad
+    LowPower,
+    /// Same frequencies as `Performance`, from the HSE crystal
+    Hse,
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PllSource {
+    Hsi,
+    Hse,
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VoltageScale {
+    Scale0,
+    Scale1,
+    Scale2,
+    Scale3,
+}
+
+impl VoltageScale {
+    /// Max (SYSCLK, HCLK, PCLKx)
+    const fn limits(self) -> (u32, u32, u32) {
+        match self {
+            Self::Scale0 => (550_000_000, 275_000_000, 137_500_000),
+            Self::Scale1 => (400_000_000, 200_000_000, 100_000_000),
+            Self::Scale2 => (300_000_000, 150_000_000, 75_000_000),
+            Self::Scale3 => (170_000_000, 85_000_000, 42_500_000),
+        }
+    }
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockError {
+    /// A prescaler or PLL divider is not a value the hardware supports
+    InvalidDivider,
+    /// The PLL input is outside 1-16MHz, contains the PLL number
+    PllInput(u8),
+    /// The VCO is outside its range, contains the PLL number
+    Vco(u8),
+    /// A PLL output used by the clock muxing is disabled
+    OutputDisabled,
+    /// SYSCLK, HCLK or a PCLK is above the limit of the voltage scale
+    BusTooFast,
+    /// The USB kernel clock is not 48MHz, contains the frequency
+    Usb(u32),
+    /// The ADC kernel clock is too fast, contains the frequency
+    Adc(u32),
+    /// The FDCAN kernel clock is too fast, contains the frequency
+    Fdcan(u32),
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pll {
+    pub prediv: u32,
+    pub mul: u32,
+    pub divp: Option<u32>,
+    pub divq: Option<u32>,
+    pub divr: Option<u32>,
+}
+
+impl Pll {
+    /// VCO frequency for the given source frequency
+    pub const fn vco(&self, source: u32) -> u32 {
+        source / self.prediv * self.mul
+    }
+
+    const fn output(&self, source: u32, div: Option<u32>) -> u32 {
+        match div {
+            Some(div) => self.vco(source) / div,
+            None => 0,
+        }
+    }
+
+    pub const fn p(&self, source: u32) -> u32 {
+        self.output(source, self.divp)
+    }
+
+    pub const fn q(&self, source: u32) -> u32 {
+        self.output(source, self.divq)
+    }
+
+    pub const fn r(&self, source: u32) -> u32 {
+        self.output(source, self.divr)
+    }
+
+    const fn validate(&self, number: u8, source: u32) -> Result<(), ClockError> {
+        const fn valid_div(div: Option<u32>) -> bool {
+            match div {
+                Some(div) => div >= 1 && div <= 128,
+                None => true,
+            }
+        }
+
+        // PLL1 P can't divide by an odd number other than 1
+        let odd_p = matches!(self.divp, Some(div) if number == 1 && div != 1 && div % 2 == 1);
+        if self.prediv < 1
+            || self.prediv > 63
+            || self.mul < 4
+            || self.mul > 512
+            || !valid_div(self.divp)
+            || !valid_div(self.divq)
+            || !valid_div(self.divr)
+            || odd_p
+        {
+            return Err(ClockError::InvalidDivider);
+        }
+
+        let input = source / self.prediv;
+        if input < PLL_INPUT_MIN || input > PLL_INPUT_MAX {
+            return Err(ClockError::PllInput(number));
+        }
+        let (vco_min, vco_max) = if input < VCO_WIDE_INPUT_MIN {
+            VCOL_RANGE
+        } else {
+            VCOH_RANGE
+        };
+        // bounding the multiplier first keeps the VCO from overflowing
+        if self.mul > vco_max / input || self.vco(source) < vco_min {
+            return Err(ClockError::Vco(number));
+        }
+        Ok(())
+    }
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockTree {
+    /// HSI prescaler, 1, 2, 4 or 8. Only used when `pll_source` is `Hsi`.
+    pub hsi_div: u32,
+    pub pll_source: PllSource,
+    pub pll1: Pll,
+    pub pll2: Pll,
+    pub pll3: Pll,
+    /// SYSCLK -> CPU
+    pub d1c_pre: u32,
+    /// CPU -> HCLK
+    pub ahb_pre: u32,
+    /// HCLK -> PCLKx
+    pub apb1_pre: u32,
+    pub apb2_pre: u32,
+    pub apb3_pre: u32,
+    pub apb4_pre: u32,
+    pub voltage_scale: VoltageScale,
+}
+
+/// Frequencies in Hz, 0 if the source is disabled
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockFrequencies {
+    pub sysclk: u32,
+    pub cpu: u32,
+    pub hclk: u32,
+    pub pclk1: u32,
+    pub pclk2: u32,
+    pub pclk3: u32,
+    pub pclk4: u32,
+    pub spi123: u32,
+    pub spi45: u32,
+    pub spi6: u32,
+    pub usart234578: u32,
+    pub i2c1235: u32,
+    pub i2c4: u32,
+    pub adc: u32,
+    pub fdcan: u32,
+    pub usb: u32,
+    pub rng: u32,
+}
+
+impl ClockProfile {
+    pub const fn tree(self) -> ClockTree {
+        match self {
+            Self::Performance => PERFORMANCE,
+            Self::LowPower => LOW_POWER,
+            Self::Hse => HSE,
+        }
+    }
+
+    pub const fn frequencies(self) -> ClockFrequencies {
+        self.tree().frequencies()
+    }
+}
+
+// a profile that breaks the limits doesn't compile
+const _: () = {
+    assert!(
+        PERFORMANCE.validate().is_ok(),
+        "Performance clock profile is invalid"
+    );
+    assert!(
+        LOW_POWER.validate().is_ok(),
+        "LowPower clock profile is invalid"
+    );
+    assert!(HSE.validate().is_ok(), "Hse clock profile is invalid");
+};
+
+impl ClockTree {
+    /// Frequency going into the PLL prescalers
+    pub const fn pll_source_frequency(&self) -> u32 {
+        match self.pll_source {
+            PllSource::Hsi => HSI_FREQUENCY / self.hsi_div,
+            PllSource::Hse => HSE_FREQUENCY,
+        }
+    }
+
+    pub const fn frequencies(&self) -> ClockFrequencies {
+        let source = self.pll_source_frequency();
+        let sysclk = self.pll1.p(source);
+        let cpu = sysclk / self.d1c_pre;
+        let hclk = cpu / self.ahb_pre;
+        let pclk1 = hclk / self.apb1_pre;
+        let pclk2 = hclk / self.apb2_pre;
+        let pclk3 = hclk / self.apb3_pre;
+        let pclk4 = hclk / self.apb4_pre;
+        ClockFrequencies {
+            sysclk,
+            cpu,
+            hclk,
+            pclk1,
+            pclk2,
+            pclk3,
+            pclk4,
+            spi123: self.pll1.q(source),
+            spi45: pclk2,
+            spi6: pclk4,
+            usart234578: pclk1,
+            i2c1235: pclk1,
+            i2c4: pclk4,
+            adc: self.pll2.p(source),
+            fdcan: self.pll1.q(source),
+            usb: self.pll3.q(source),
+            rng: HSI48_FREQUENCY,
+        }
+    }
+
+    /// Computes the frequencies and checks them against the limits of the STM32H723
+    pub const fn validate(&self) -> Result<ClockFrequencies, ClockError> {
+        const fn is_power_of_two_up_to(div: u32, max: u32) -> bool {
+            div.is_power_of_two() && div <= max
+        }
+
+        // the AHB prescalers have no /32
+        const fn ahb_valid(div: u32) -> bool {
+            is_power_of_two_up_to(div, 512) && div != 32
+        }
+
+        if !is_power_of_two_up_to(self.hsi_div, 8)
+            || !ahb_valid(self.d1c_pre)
+            || !ahb_valid(self.ahb_pre)
+            || !is_power_of_two_up_to(self.apb1_pre, 16)
+            || !is_power_of_two_up_to(self.apb2_pre, 16)
+            || !is_power_of_two_up_to(self.apb3_pre, 16)
+            || !is_power_of_two_up_to(self.apb4_pre, 16)
+        {
+            return Err(ClockError::InvalidDivider);
+        }
+
+        let source = self.pll_source_frequency();
+        if let Err(e) = self.pll1.validate(1, source) {
+            return Err(e);
+        }
+        if let Err(e) = self.pll2.validate(2, source) {
+            return Err(e);
+        }
+        if let Err(e) = self.pll3.validate(3, source) {
+            return Err(e);
+        }
+        if self.pll1.divp.is_none()
+            || self.pll1.divq.is_none()
+            || self.pll2.divp.is_none()
+            || self.pll3.divq.is_none()
+        {
+            return Err(ClockError::OutputDisabled);
+        }
+
+        let f = self.frequencies();
+        let (sysclk_max, hclk_max, pclk_max) = self.voltage_scale.limits();
+        if f.sysclk > sysclk_max
+            || f.hclk > hclk_max
+            || f.pclk1 > pclk_max
+            || f.pclk2 > pclk_max
+            || f.pclk3 > pclk_max
+            || f.pclk4 > pclk_max
+        {
+            return Err(ClockError::BusTooFast);
+        }
+        if f.usb != USB_FREQUENCY {
+            return Err(ClockError::Usb(f.usb));
+        }
+        if f.adc > ADC_MAX {
+            return Err(ClockError::Adc(f.adc));
+        }
+        if f.fdcan > FDCAN_MAX {
+            return Err(ClockError::Fdcan(f.fdcan));
+        }
+        Ok(f)
+    }
+}
+
+// 16MHz PLL input
+const PERFORMANCE: ClockTree = ClockTree {
+    hsi_div: 4,
+    pll_source: PllSource::Hsi,
+    pll1: Pll {
+        prediv: 1,
+        mul: 32,
+        divp: Some(1),
+        divq: Some(4),
+        divr: Some(2),
+    },
+    pll2: Pll {
+        prediv: 1,
+        mul: 20,
+        divp: Some(8),
+        divq: Some(2),
+        divr: Some(2),
+    },
+    pll3: Pll {
+        prediv: 1,
+        mul: 24,
+        divp: Some(2),
+        divq: Some(8),
+        divr: Some(2),
+    },
+    d1c_pre: 1,
+    ahb_pre: 2,
+    apb1_pre: 2,
+    apb2_pre: 2,
+    apb3_pre: 2,
+    apb4_pre: 2,
+    voltage_scale: VoltageScale::Scale0,
+};
+
+// 16MHz PLL input, PLL3 is kept for USB
+const LOW_POWER: ClockTree = ClockTree {
+    pll1: Pll {
+        prediv: 1,
+        mul: 20,
+        divp: Some(4),
+        divq: Some(4),
+        divr: Some(2),
+    },
+    pll2: Pll {
+        prediv: 1,
+        mul: 20,
+        divp: Some(16),
+        divq: Some(2),
+        divr: Some(2),
+    },
+    ahb_pre: 1,
+    voltage_scale: VoltageScale::Scale3,
+    ..PERFORMANCE
+};
+
+// 2MHz PLL input
+const HSE: ClockTree = ClockTree {
+    pll_source: PllSource::Hse,
+    pll1: Pll {
+        prediv: HSE_FREQUENCY / 2_000_000,
+        mul: 256,
+        ..PERFORMANCE.pll1
+    },
+    pll2: Pll {
+        prediv: HSE_FREQUENCY / 2_000_000,
+        mul: 160,
+        ..PERFORMANCE.pll2
+    },
+    pll3: Pll {
+        prediv: HSE_FREQUENCY / 2_000_000,
+        mul: 192,
+        ..PERFORMANCE.pll3
+    },
+    ..PERFORMANCE
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profile_frequencies() {
+        let performance = ClockProfile::Performance.frequencies();
+        assert_eq!(performance.sysclk, 512_000_000);
+        assert_eq!(performance.hclk, 256_000_000);
+        assert_eq!(performance.pclk1, 128_000_000);
+        assert_eq!(performance.adc, 40_000_000);
+        assert_eq!(ClockProfile::Hse.frequencies(), performance);
+
+        let low_power = ClockProfile::LowPower.frequencies();
+        assert_eq!(low_power.sysclk, 80_000_000);
+        assert_eq!(low_power.hclk, 80_000_000);
+        assert_eq!(low_power.usb, 48_000_000);
+    }
+
+    #[test]
+    fn rejects_bad_edits() {
+        let mut tree = ClockProfile::Performance.tree();
+        assert_eq!(tree.validate(), Ok(ClockProfile::Performance.frequencies()));
+
+        tree.pll3.divq = Some(4);
+        assert_eq!(tree.validate(), Err(ClockError::Usb(96_000_000)));
+        tree = PERFORMANCE;
+        tree.pll2.divp = Some(4);
+        assert_eq!(tree.validate(), Err(ClockError::Adc(80_000_000)));
+        tree = PERFORMANCE;
+        tree.pll1.mul = 64;
+        assert_eq!(tree.validate(), Err(ClockError::Vco(1)));
+        // 16MHz * 300 doesn't fit in a u32
+        tree.pll1.mul = 300;
+        assert_eq!(tree.validate(), Err(ClockError::Vco(1)));
+        tree = PERFORMANCE;
+        tree.pll1.divp = Some(3);
+        assert_eq!(tree.validate(), Err(ClockError::InvalidDivider));
+        tree = PERFORMANCE;
+        tree.voltage_scale = VoltageScale::Scale1;
+        assert_eq!(tree.validate(), Err(ClockError::BusTooFast));
+        tree = PERFORMANCE;
+        tree.hsi_div = 1;
+        assert_eq!(tree.validate(), Err(ClockError::PllInput(1)));
+    }
+}
@@ -0,0 +1,360 @@
+// Startup configuration of a u-blox M8 receiver over UBX. Every command is retried until the
+// receiver ACKs or NAKs it, a receiver that doesn't answer keeps running on its defaults
+// (NMEA at 9600 baud, 1Hz, portable dynamic model).
+
+use crate::gps_demux::{GpsDemux, GpsPacket};
+use crate::ubx::{
+    CLASS_CFG, CLASS_NAV, CLASS_NMEA, CLASS_TIM, ID_CFG_MSG, ID_CFG_NAV5, ID_CFG_PRT, ID_CFG_RATE,
+    ID_NAV_PVT, ID_NMEA_GLL, ID_NMEA_GSV, ID_NMEA_VTG, ID_TIM_TP, MAX_PAYLOAD, UbxFrame,
+    UbxMessage,
+};
+use embassy_time::{Duration, Timer, with_timeout};
+use embedded_io_async::{Read, Write};
+
+/// Baud rate of a receiver with the factory configuration
+pub const DEFAULT_BAUD_RATE: u32 = 9600;
+/// Baud rates tried by `detect_baud_rate`, most likely first
+pub const COMMON_BAUD_RATES: [u32; 8] = [
+    9600, 115_200, 38_400, 57_600, 230_400, 460_800, 19_200, 4800,
+];
+const ATTEMPTS: usize = 3;
+// time for the receiver to switch after the CFG-PRT left the UART
+const BAUD_SWITCH_DELAY: Duration = Duration::from_millis(100);
+
+/// UART whose baud rate can be changed while it is open
+pub trait SetBaudRate {
+    /// Returns false if the UART doesn't support the baud rate
+    fn set_baud_rate(&mut self, baud_rate: u32) -> bool;
+}
+
+/// CFG-NAV5 dynamic platform model
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum DynamicModel {
+    Portable = 0,
+    Stationary = 2,
+    Pedestrian = 3,
+    Automotive = 4,
+    Sea = 5,
+    /// <1g, no altitude limit below 50km
+    Airborne1g = 6,
+    /// <2g
+    Airborne2g = 7,
+    /// <4g
+    Airborne4g = 8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GpsConfig<'a> {
+    pub baud_rate: u32,
+    /// Time between navigation solutions, 100 to 200ms for 5-10Hz
+    pub measurement_period_ms: u16,
+    pub dynamic_model: DynamicModel,
+    /// Output rates as (class, id, rate), the rate is per navigation solution and 0 disables
+    /// the message
+    pub messages: &'a [(u8, u8, u8)],
+    /// How long to wait for the ACK of each command
+    pub ack_timeout: Duration,
+}
+
+/// Drops the NMEA sentences that repeat RMC and GGA, keeps GSV at 1Hz and adds the UBX
+/// position and time pulse messages
+pub const DEFAULT_MESSAGES: [(u8, u8, u8); 5] = [
+    (CLASS_NMEA, ID_NMEA_GLL, 0),
+    (CLASS_NMEA, ID_NMEA_VTG, 0),
+    (CLASS_NMEA, ID_NMEA_GSV, 5),
+    (CLASS_NAV, ID_NAV_PVT, 1),
+    (CLASS_TIM, ID_TIM_TP, 1),
+];
+
+impl Default for GpsConfig<'static> {
+    fn default() -> Self {
+        Self {
+            baud_rate: 115_200,
+            measurement_period_ms: 200,
+            dynamic_model: DynamicModel::Airborne4g,
+            messages: &DEFAULT_MESSAGES,
+            ack_timeout: Duration::from_millis(500),
+        }
+    }
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StepResult {
+    Acked,
+    Rejected,
+    NoResponse,
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GpsConfigReport {
+    /// Baud rate the UART was left at
+    pub baud_rate: u32,
+    pub rate: StepResult,
+    /// The first message that wasn't acked, if any
+    pub messages: StepResult,
+    pub dynamic_model: StepResult,
+}
+
+impl GpsConfigReport {
+    /// Whether every command was acked
+    pub fn complete(&self) -> bool {
+        self.rate == StepResult::Acked
+            && self.messages == StepResult::Acked
+            && self.dynamic_model == StepResult::Acked
+    }
+}
+
+/// Configures the receiver connected to `uart`, which is open at `baud_rate`. If the receiver
+/// doesn't answer at the new baud rate the UART goes back to `baud_rate`. Anything the
+/// receiver sends during the configuration is dropped.
+pub async fn configure_gps<U>(
+    uart: &mut U,
+    baud_rate: u32,
+    config: &GpsConfig<'_>,
+) -> Result<GpsConfigReport, U::Error>
+where
+    U: Read + Write + SetBaudRate,
+{
+    let mut demux = GpsDemux::new();
+    let mut report = GpsConfigReport {
+        baud_rate,
+        rate: StepResult::NoResponse,
+        messages: StepResult::Acked,
+        dynamic_model: StepResult::NoResponse,
+    };
+    let rate = cfg_rate_payload(config.measurement_period_ms);
+    let rate = UbxFrame::new(CLASS_CFG, ID_CFG_RATE, &rate);
+
+    if config.baud_rate != baud_rate {
+        // the ACK is sent at the new baud rate and usually lost, the next command checks
+        // whether the receiver followed
+        let prt = cfg_prt_payload(config.baud_rate);
+        send(uart, &UbxFrame::new(CLASS_CFG, ID_CFG_PRT, &prt)).await?;
+        uart.flush().await?;
+        Timer::after(BAUD_SWITCH_DELAY).await;
+        if uart.set_baud_rate(config.baud_rate) {
+            report.baud_rate = config.baud_rate;
+            report.rate = command(uart, &mut demux, &rate, config.ack_timeout).await?;
+            if report.rate == StepResult::NoResponse && uart.set_baud_rate(baud_rate) {
+                report.baud_rate = baud_rate;
+            }
+        }
+    }
+    if report.baud_rate == baud_rate {
+        report.rate = command(uart, &mut demux, &rate, config.ack_timeout).await?;
+    }
+
+    for &(class, id, rate) in config.messages {
+        let payload = [class, id, rate];
+        let msg = UbxFrame::new(CLASS_CFG, ID_CFG_MSG, &payload);
+        let result = command(uart, &mut demux, &msg, config.ack_timeout).await?;
+        if result != StepResult::Acked && report.messages == StepResult::Acked {
+            report.messages = result;
+        }
+    }
+
+    let nav5 = cfg_nav5_payload(config.dynamic_model);
+    let nav5 = UbxFrame::new(CLASS_CFG, ID_CFG_NAV5, &nav5);
+    report.dynamic_model = command(uart, &mut demux, &nav5, config.ack_timeout).await?;
+
+    Ok(report)
+}
+
+/// Tries each baud rate until a sentence or frame with a valid checksum arrives. The receiver
+/// is polled for its port configuration in case it has no periodic output. Returns the baud
+/// rate the UART was left at, or `None` with the UART at the first rate if nothing answered.
+pub async fn detect_baud_rate<U>(
+    uart: &mut U,
+    baud_rates: &[u32],
+    listen: Duration,
+) -> Result<Option<u32>, U::Error>
+where
+    U: Read + Write + SetBaudRate,
+{
+    for &baud_rate in baud_rates {
+        if !uart.set_baud_rate(baud_rate) {
+            continue;
+        }
+        send(uart, &UbxFrame::new(CLASS_CFG, ID_CFG_PRT, &[])).await?;
+        if let Ok(true) = with_timeout(listen, wait_for_packet(uart)).await {
+            return Ok(Some(baud_rate));
+        }
+    }
+    if let Some(&baud_rate) = baud_rates.first() {
+        uart.set_baud_rate(baud_rate);
+    }
+    Ok(None)
+}
+
+/// Whether a valid packet arrived before the UART ran out of data
+async fn wait_for_packet<U: Read>(uart: &mut U) -> bool {
+    // starts empty so bytes received at the previous baud rate don't count
+    let mut demux = GpsDemux::new();
+    let mut buffer = [0; 64];
+    loop {
+        let length = match uart.read(&mut buffer).await {
+            Ok(0) => return false,
+            Ok(length) => length,
+            // framing and noise errors are expected at the wrong baud rate
+            Err(_) => continue,
+        };
+        let mut bytes = &buffer[..length];
+        while !bytes.is_empty() {
+            let (used, packet) = demux.push_slice(bytes);
+            if packet.is_some() {
+                return true;
+            }
+            bytes = &bytes[used..];
+        }
+    }
+}
+
+/// Sends the frame until the receiver answers with an ACK or NAK
+pub async fn command<U: Read + Write>(
+    uart: &mut U,
+    demux: &mut GpsDemux,
+    frame: &UbxFrame<'_>,
+    timeout: Duration,
+) -> Result<StepResult, U::Error> {
+    for _ in 0..ATTEMPTS {
+        send(uart, frame).await?;
+        match with_timeout(timeout, wait_for_ack(uart, demux, frame.class, frame.id)).await {
+            Ok(Some(true)) => return Ok(StepResult::Acked),
+            Ok(Some(false)) => return Ok(StepResult::Rejected),
+            Ok(None) | Err(_) => {}
+        }
+    }
+    Ok(StepResult::NoResponse)
+}
+
+/// Some(acked) once the receiver answered the message with `class` and `id`, `None` if the
+/// UART has nothing more to read
+async fn wait_for_ack<U: Read>(
+    uart: &mut U,
+    demux: &mut GpsDemux,
+    class: u8,
+    id: u8,
+) -> Option<bool> {
+    let mut buffer = [0; 64];
+    loop {
+        let length = match uart.read(&mut buffer).await {
+            Ok(0) | Err(_) => return None,
+            Ok(length) => length,
+        };
+        let mut bytes = &buffer[..length];
+        while !bytes.is_empty() {
+            let (used, packet) = demux.push_slice(bytes);
+            bytes = &bytes[used..];
+            let Some(GpsPacket::Ubx(frame)) = packet else {
+                continue;
+            };
+            match UbxMessage::parse(&frame) {
+                Some(UbxMessage::Ack { class: c, id: i }) if (c, i) == (class, id) => {
+                    return Some(true);
+                }
+                Some(UbxMessage::Nak { class: c, id: i }) if (c, i) == (class, id) => {
+                    return Some(false);
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Frames with a payload over `MAX_PAYLOAD` are dropped
+pub async fn send<U: Write>(uart: &mut U, frame: &UbxFrame<'_>) -> Result<(), U::Error> {
+    let mut buffer = [0; MAX_PAYLOAD + 8];
+    if let Some(bytes) = frame.encode(&mut buffer) {
+        uart.write_all(bytes).await?;
+    }
+    Ok(())
+}
+
+/// CFG-PRT for UART1, 8N1 with UBX and NMEA in both directions
+pub fn cfg_prt_payload(baud_rate: u32) -> [u8; 20] {
+    let mut payload = [0; 20];
+    payload[0] = 1;
+    payload[4..8].copy_from_slice(&0x0000_08D0u32.to_le_bytes());
+    payload[8..12].copy_from_slice(&baud_rate.to_le_bytes());
+    payload[12..14].copy_from_slice(&0x0003u16.to_le_bytes());
+    payload[14..16].copy_from_slice(&0x0003u16.to_le_bytes());
+    payload
+}
+
+/// CFG-RATE with one navigation solution per measurement, aligned to GPS time
+pub fn cfg_rate_payload(measurement_period_ms: u16) -> [u8; 6] {
+    let mut payload = [0; 6];
+    payload[0..2].copy_from_slice(&measurement_period_ms.to_le_bytes());
+    payload[2..4].copy_from_slice(&1u16.to_le_bytes());
+    payload[4..6].copy_from_slice(&1u16.to_le_bytes());
+    payload
+}
+
+/// CFG-NAV5 that only changes the dynamic model
+pub fn cfg_nav5_payload(model: DynamicModel) -> [u8; 36] {
+    let mut payload = [0; 36];
+    payload[0..2].copy_from_slice(&0x0001u16.to_le_bytes());
+    payload[2] = model as u8;
+    payload
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::SimulatedUblox;
+    use embassy_futures::block_on;
+
+    #[test]
+    fn configures_receiver() {
+        let mut gps = SimulatedUblox::new(DEFAULT_BAUD_RATE);
+        let config = GpsConfig::default();
+        let report = block_on(configure_gps(&mut gps, DEFAULT_BAUD_RATE, &config)).unwrap();
+        assert!(report.complete());
+        assert_eq!(report.baud_rate, 115_200);
+        assert_eq!(gps.baud_rate, 115_200);
+        assert_eq!(gps.measurement_period_ms, 200);
+        assert_eq!(gps.dynamic_model, DynamicModel::Airborne4g as u8);
+        assert_eq!(gps.message_rate(CLASS_NMEA, ID_NMEA_GLL), Some(0));
+        assert_eq!(gps.message_rate(CLASS_NAV, ID_NAV_PVT), Some(1));
+    }
+
+    #[test]
+    fn falls_back_when_receiver_does_not_switch() {
+        let config = GpsConfig {
+            ack_timeout: Duration::from_millis(10),
+            ..GpsConfig::default()
+        };
+
+        let mut gps = SimulatedUblox::new(DEFAULT_BAUD_RATE);
+        gps.ignored.push(ID_CFG_PRT).unwrap();
+        gps.rejected.push(ID_CFG_NAV5).unwrap();
+        let report = block_on(configure_gps(&mut gps, DEFAULT_BAUD_RATE, &config)).unwrap();
+        assert_eq!(report.baud_rate, DEFAULT_BAUD_RATE);
+        assert_eq!(report.rate, StepResult::Acked);
+        assert_eq!(report.dynamic_model, StepResult::Rejected);
+        assert_eq!(gps.measurement_period_ms, 200);
+
+        let mut gps = SimulatedUblox::new(DEFAULT_BAUD_RATE);
+        gps.silent = true;
+        let report = block_on(configure_gps(&mut gps, DEFAULT_BAUD_RATE, &config)).unwrap();
+        assert_eq!(report.baud_rate, DEFAULT_BAUD_RATE);
+        assert_eq!(report.rate, StepResult::NoResponse);
+        assert_eq!(report.messages, StepResult::NoResponse);
+    }
+
+    #[test]
+    fn detects_baud_rate() {
+        let listen = Duration::from_millis(10);
+        let mut gps = SimulatedUblox::new(38_400);
+        gps.host_baud_rate = DEFAULT_BAUD_RATE;
+        let detected = block_on(detect_baud_rate(&mut gps, &COMMON_BAUD_RATES, listen));
+        assert_eq!(detected, Ok(Some(38_400)));
+        assert_eq!(gps.host_baud_rate, 38_400);
+
+        gps.silent = true;
+        let detected = block_on(detect_baud_rate(&mut gps, &COMMON_BAUD_RATES, listen));
+        assert_eq!(detected, Ok(None));
+        assert_eq!(gps.host_baud_rate, DEFAULT_BAUD_RATE);
+    }
+}
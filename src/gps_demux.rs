@@ -0,0 +1,95 @@
+use crate::nmea_framer::NmeaFramer;
+use crate::ubx::{UbxDecoder, UbxFrame};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GpsPacket<'a> {
+    /// Sentence without its line ending
+    Nmea(&'a str),
+    Ubx(UbxFrame<'a>),
+}
+
+/// Separates NMEA sentences and UBX frames arriving on the same UART. NMEA is printable ASCII
+/// and can't contain the UBX sync bytes, UBX payloads are kept away from the NMEA framer.
+#[derive(Debug, Default)]
+pub struct GpsDemux {
+    pub nmea: NmeaFramer,
+    pub ubx: UbxDecoder,
+}
+
+impl GpsDemux {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push(&mut self, byte: u8) -> Option<GpsPacket<'_>> {
+        let packet = self.step(byte)?;
+        Some(self.packet(packet))
+    }
+
+    /// Pushes bytes until a packet is complete. Returns how many bytes were used and the
+    /// packet, call again with the rest of the chunk.
+    pub fn push_slice(&mut self, bytes: &[u8]) -> (usize, Option<GpsPacket<'_>>) {
+        for (i, &byte) in bytes.iter().enumerate() {
+            if let Some(packet) = self.step(byte) {
+                return (i + 1, Some(self.packet(packet)));
+            }
+        }
+        (bytes.len(), None)
+    }
+
+    fn step(&mut self, byte: u8) -> Option<Protocol> {
+        if self.ubx.in_frame() {
+            return self.ubx.push(byte).map(|_| Protocol::Ubx);
+        }
+        // outside of a frame the decoder only looks for the sync bytes
+        self.ubx.push(byte);
+        self.nmea.push(byte).map(|_| Protocol::Nmea)
+    }
+
+    fn packet(&self, protocol: Protocol) -> GpsPacket<'_> {
+        match protocol {
+            Protocol::Nmea => GpsPacket::Nmea(self.nmea.last_sentence()),
+            Protocol::Ubx => GpsPacket::Ubx(self.ubx.last_frame()),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Protocol {
+    Nmea,
+    Ubx,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ubx::{CLASS_ACK, ID_ACK_ACK};
+
+    #[test]
+    fn separates_nmea_and_ubx() {
+        // the payload contains '$' and a line feed
+        let mut buffer = [0; 16];
+        let frame = UbxFrame::new(CLASS_ACK, ID_ACK_ACK, b"$\n");
+        let mut stream = std::vec::Vec::from(b"$GPGGA,1,2*55\r\n$GPRMC,");
+        stream.extend_from_slice(frame.encode(&mut buffer).unwrap());
+        stream.extend_from_slice(b"$GPRMC,x*1F\r\n");
+
+        let mut demux = GpsDemux::new();
+        let mut packets = std::vec::Vec::new();
+        let mut bytes = &stream[..];
+        while !bytes.is_empty() {
+            let (used, packet) = demux.push_slice(bytes);
+            bytes = &bytes[used..];
+            match packet {
+                Some(GpsPacket::Nmea(sentence)) => {
+                    packets.push(std::string::String::from(sentence))
+                }
+                Some(GpsPacket::Ubx(frame)) => packets.push(std::format!("{:?}", frame.payload)),
+                None => {}
+            }
+        }
+        assert_eq!(packets, ["$GPGGA,1,2*55", "[36, 10]", "$GPRMC,x*1F"]);
+        // the sentence cut short by the UBX frame
+        assert_eq!(demux.nmea.stats().framing_errors, 1);
+    }
+}
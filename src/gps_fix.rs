@@ -0,0 +1,195 @@
+// Navigation solution in SI units, built from either the NMEA sentences or UBX-NAV-PVT and
+// published to every task that needs the position. Fields the source doesn't report are `None`.
+
+use crate::ubx::NavPvt;
+use chrono::{TimeZone as _, Utc};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::watch::Watch;
+use nmea::Nmea;
+use nmea::sentences::FixType;
+
+const KNOTS_TO_MPS: f32 = 1852.0 / 3600.0;
+
+/// Latest fix for up to `N` receivers
+pub type GpsFixWatch<const N: usize> = Watch<CriticalSectionRawMutex, GpsFix, N>;
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GpsFixType {
+    NoFix,
+    DeadReckoning,
+    Fix2d,
+    Fix3d,
+    /// 3D with differential corrections
+    Differential,
+    /// 3D with carrier phase corrections, float or fixed ambiguities
+    Rtk,
+}
+
+impl GpsFixType {
+    pub fn has_position(self) -> bool {
+        !matches!(self, Self::NoFix | Self::DeadReckoning)
+    }
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq)]
+pub struct GpsFix {
+    pub fix_type: GpsFixType,
+    /// deg, positive north
+    pub latitude: Option<f64>,
+    /// deg, positive east
+    pub longitude: Option<f64>,
+    /// Height above mean sea level, m
+    pub altitude_msl: Option<f32>,
+    /// Height above the WGS84 ellipsoid, m
+    pub altitude_ellipsoid: Option<f32>,
+    /// m/s
+    pub ground_speed: Option<f32>,
+    /// Course over ground, deg from true north
+    pub course: Option<f32>,
+    pub pdop: Option<f32>,
+    pub hdop: Option<f32>,
+    pub vdop: Option<f32>,
+    pub satellites_used: Option<u8>,
+    /// UTC time of the solution, ns since the unix epoch
+    pub unix_time_ns: Option<i64>,
+    /// Local time the solution was received, ns on the `Instant` timebase
+    pub received_ns: i64,
+}
+
+impl GpsFix {
+    /// From everything parsed into `nmea` so far, received at local time `received_ns`
+    pub fn from_nmea(nmea: &Nmea, received_ns: i64) -> Self {
+        let fix_type = match nmea.fix_type {
+            None | Some(FixType::Invalid | FixType::Manual | FixType::Simulation) => {
+                GpsFixType::NoFix
+            }
+            Some(FixType::Estimated) => GpsFixType::DeadReckoning,
+            // GGA doesn't tell 2D from 3D
+            Some(FixType::Gps | FixType::Pps) if nmea.altitude.is_none() => GpsFixType::Fix2d,
+            Some(FixType::Gps | FixType::Pps) => GpsFixType::Fix3d,
+            Some(FixType::DGps) => GpsFixType::Differential,
+            Some(FixType::Rtk | FixType::FloatRtk) => GpsFixType::Rtk,
+        };
+        let unix_time_ns = nmea.fix_date.zip(nmea.fix_time).and_then(|(date, time)| {
+            Utc.from_utc_datetime(&date.and_time(time))
+                .timestamp_nanos_opt()
+        });
+        Self {
+            fix_type,
+            latitude: nmea.latitude,
+            longitude: nmea.longitude,
+            altitude_msl: nmea.altitude,
+            // the geoid separation is the height of the geoid above the ellipsoid
+            altitude_ellipsoid: nmea
+                .altitude
+                .zip(nmea.geoid_separation)
+                .map(|(altitude, separation)| altitude + separation),
+            ground_speed: nmea.speed_over_ground.map(|knots| knots * KNOTS_TO_MPS),
+            course: nmea.true_course,
+            pdop: nmea.pdop,
+            hdop: nmea.hdop,
+            vdop: nmea.vdop,
+            satellites_used: nmea
+                .num_of_fix_satellites
+                .map(|n| n.min(u8::MAX as u32) as u8),
+            unix_time_ns,
+            received_ns,
+        }
+    }
+
+    /// From a NAV-PVT received at local time `received_ns`. NAV-PVT has no HDOP and VDOP.
+    pub fn from_nav_pvt(pvt: &NavPvt, received_ns: i64) -> Self {
+        let fix_type = match pvt.fix_type {
+            _ if !pvt.fix_ok() => GpsFixType::NoFix,
+            1 => GpsFixType::DeadReckoning,
+            2 => GpsFixType::Fix2d,
+            3 | 4 if pvt.carrier_solution() != 0 => GpsFixType::Rtk,
+            3 | 4 if pvt.differential() => GpsFixType::Differential,
+            3 | 4 => GpsFixType::Fix3d,
+            _ => GpsFixType::NoFix,
+        };
+        let position = fix_type.has_position();
+        Self {
+            fix_type,
+            latitude: position.then_some(pvt.lat as f64 / 1e7),
+            longitude: position.then_some(pvt.lon as f64 / 1e7),
+            altitude_msl: position.then_some(pvt.height_msl as f32 / 1e3),
+            altitude_ellipsoid: position.then_some(pvt.height as f32 / 1e3),
+            ground_speed: position.then_some(pvt.ground_speed as f32 / 1e3),
+            course: position.then_some(pvt.heading as f32 / 1e5),
+            pdop: Some(pvt.pdop as f32 / 100.0),
+            hdop: None,
+            vdop: None,
+            satellites_used: Some(pvt.num_sv),
+            unix_time_ns: pvt
+                .unix_time()
+                .map(|seconds| seconds * 1_000_000_000 + pvt.nano as i64),
+            received_ns,
+        }
+    }
+
+    /// Time since the solution was received, ns
+    pub fn age_ns(&self, now_ns: i64) -> i64 {
+        now_ns - self.received_ns
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, NaiveTime};
+
+    #[test]
+    fn converts_nmea_fix() {
+        let mut nmea = Nmea::default();
+        assert_eq!(GpsFix::from_nmea(&nmea, 0).fix_type, GpsFixType::NoFix);
+
+        nmea.fix_type = Some(FixType::Gps);
+        nmea.latitude = Some(43.26);
+        nmea.longitude = Some(-79.92);
+        nmea.altitude = Some(100.0);
+        nmea.geoid_separation = Some(-35.0);
+        nmea.speed_over_ground = Some(10.0);
+        nmea.num_of_fix_satellites = Some(9);
+        nmea.fix_date = NaiveDate::from_ymd_opt(2024, 6, 15);
+        nmea.fix_time = NaiveTime::from_hms_milli_opt(12, 30, 5, 200);
+        let fix = GpsFix::from_nmea(&nmea, 5_000_000_000);
+        assert_eq!(fix.fix_type, GpsFixType::Fix3d);
+        assert_eq!(fix.altitude_ellipsoid, Some(65.0));
+        assert!((fix.ground_speed.unwrap() - 5.144).abs() < 0.001);
+        assert_eq!(fix.satellites_used, Some(9));
+        assert_eq!(fix.unix_time_ns, Some(1_718_454_605_200_000_000));
+        assert_eq!(fix.age_ns(5_250_000_000), 250_000_000);
+    }
+
+    #[test]
+    fn converts_nav_pvt() {
+        let mut payload = [0u8; NavPvt::LEN];
+        payload[4..6].copy_from_slice(&2024u16.to_le_bytes());
+        payload[6..11].copy_from_slice(&[6, 15, 12, 30, 5]);
+        payload[11] = 0x07;
+        payload[16..20].copy_from_slice(&(-1_000_000i32).to_le_bytes());
+        payload[20] = 3;
+        payload[21] = 0x03;
+        payload[28..32].copy_from_slice(&432_600_000i32.to_le_bytes());
+        payload[32..36].copy_from_slice(&65_000i32.to_le_bytes());
+        payload[36..40].copy_from_slice(&100_000i32.to_le_bytes());
+        payload[76..78].copy_from_slice(&150u16.to_le_bytes());
+        let pvt = NavPvt::parse(&payload).unwrap();
+
+        let fix = GpsFix::from_nav_pvt(&pvt, 0);
+        assert_eq!(fix.fix_type, GpsFixType::Differential);
+        assert!((fix.latitude.unwrap() - 43.26).abs() < 1e-9);
+        assert_eq!(fix.altitude_msl, Some(100.0));
+        assert_eq!(fix.altitude_ellipsoid, Some(65.0));
+        assert_eq!(fix.pdop, Some(1.5));
+        // the fraction is negative, the solution is just before 12:30:05
+        assert_eq!(fix.unix_time_ns, Some(1_718_454_604_999_000_000));
+
+        // without gnssFixOK the position isn't reported
+        payload[21] = 0x00;
+        let fix = GpsFix::from_nav_pvt(&NavPvt::parse(&payload).unwrap(), 0);
+        assert_eq!(fix.fix_type, GpsFixType::NoFix);
+        assert_eq!(fix.latitude, None);
+    }
+}
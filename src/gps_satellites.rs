@@ -0,0 +1,498 @@
+// Satellites in view and in use, from GSV and GSA. GSV lists every satellite a constellation has
+// in view with its SNR, split over several sentences. GSA lists the PRNs used in the solution,
+// one sentence per constellation. Receivers on NMEA 4.10 and later add a system id to GSA, older
+// ones send GNGSA with PRN ranges that don't overlap between constellations.
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::watch::Watch;
+use heapless::Vec;
+use nmea::sentences::{GnssType, GsaData, GsaMode2, GsvData};
+use nmea::{ParseResult, parse_str};
+
+/// Satellites kept from GSV, u-blox tracks up to 72 but reports the strongest
+pub const MAX_SATELLITES: usize = 64;
+/// Used satellites kept from GSA
+pub const MAX_USED: usize = 32;
+/// SNR a satellite needs to count as a strong signal, dB-Hz
+pub const STRONG_SNR: u8 = 30;
+
+/// Latest satellite status for up to `N` receivers
+pub type SatelliteStatusWatch<const N: usize> = Watch<CriticalSectionRawMutex, SatelliteStatus, N>;
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Constellation {
+    Gps,
+    Glonass,
+    Galileo,
+    Beidou,
+    Qzss,
+    NavIc,
+}
+
+impl Constellation {
+    pub const ALL: [Self; 6] = [
+        Self::Gps,
+        Self::Glonass,
+        Self::Galileo,
+        Self::Beidou,
+        Self::Qzss,
+        Self::NavIc,
+    ];
+
+    fn from_gnss_type(gnss_type: GnssType) -> Self {
+        match gnss_type {
+            GnssType::Gps => Self::Gps,
+            GnssType::Glonass => Self::Glonass,
+            GnssType::Galileo => Self::Galileo,
+            GnssType::Beidou => Self::Beidou,
+            GnssType::Qzss => Self::Qzss,
+            GnssType::NavIC => Self::NavIc,
+        }
+    }
+
+    /// NMEA 4.10 system id
+    fn from_system_id(id: &str) -> Option<Self> {
+        Some(match id {
+            "1" => Self::Gps,
+            "2" => Self::Glonass,
+            "3" => Self::Galileo,
+            "4" => Self::Beidou,
+            "5" => Self::Qzss,
+            "6" => Self::NavIc,
+            _ => return None,
+        })
+    }
+
+    fn from_talker(talker: &str) -> Option<Self> {
+        Some(match talker {
+            "GP" => Self::Gps,
+            "GL" => Self::Glonass,
+            "GA" => Self::Galileo,
+            "GB" | "BD" => Self::Beidou,
+            "GQ" => Self::Qzss,
+            "GI" => Self::NavIc,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FixMode {
+    NoFix,
+    Fix2d,
+    Fix3d,
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Satellite {
+    pub constellation: Constellation,
+    pub prn: u32,
+    /// dB-Hz, `None` while not tracked
+    pub snr: Option<u8>,
+    /// deg
+    pub elevation: Option<u8>,
+    pub used: bool,
+}
+
+#[derive(defmt::Format, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ConstellationCount {
+    /// As reported by GSV, can be more than the satellites listed
+    pub in_view: u8,
+    pub used: u8,
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GpsHealth {
+    /// 3D fix with enough strong satellites and good geometry
+    Good,
+    /// A fix, but weak signals, few satellites or poor geometry
+    Degraded,
+    /// No fix or too few strong signals, the antenna may be obstructed
+    Bad,
+}
+
+/// Summary of `SatelliteStatus` for logging and telemetry
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq)]
+pub struct SatelliteSummary {
+    /// Indexed like `Constellation::ALL`
+    pub constellations: [ConstellationCount; 6],
+    /// Satellites with an SNR of at least `STRONG_SNR`
+    pub strong: u8,
+    pub max_snr: Option<u8>,
+    pub fix_mode: FixMode,
+    pub pdop: Option<f32>,
+    pub hdop: Option<f32>,
+    pub vdop: Option<f32>,
+    pub health: GpsHealth,
+}
+
+#[derive(Debug, Clone)]
+pub struct SatelliteStatus {
+    /// From GSV, without the used flag
+    satellites: Vec<Satellite, MAX_SATELLITES>,
+    in_view: [u8; 6],
+    /// From GSA, the constellation is unknown for GNGSA without a system id
+    used: Vec<(Option<Constellation>, u32), MAX_USED>,
+    fix_mode: FixMode,
+    pdop: Option<f32>,
+    hdop: Option<f32>,
+    vdop: Option<f32>,
+    /// GSV group being received, only applied once complete
+    gsv_group: Option<GsvGroup>,
+    /// GSA sentences of the current epoch, applied once another sentence ends the group
+    gsa_group: Option<GsaGroup>,
+}
+
+#[derive(Debug, Clone)]
+struct GsvGroup {
+    constellation: Constellation,
+    sentences: u16,
+    next_sentence: u16,
+    in_view: u8,
+    satellites: Vec<Satellite, MAX_SATELLITES>,
+}
+
+#[derive(Debug, Clone)]
+struct GsaGroup {
+    used: Vec<(Option<Constellation>, u32), MAX_USED>,
+    fix_mode: FixMode,
+    pdop: Option<f32>,
+    hdop: Option<f32>,
+    vdop: Option<f32>,
+}
+
+impl Default for SatelliteStatus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SatelliteStatus {
+    pub const fn new() -> Self {
+        Self {
+            satellites: Vec::new(),
+            in_view: [0; 6],
+            used: Vec::new(),
+            fix_mode: FixMode::NoFix,
+            pdop: None,
+            hdop: None,
+            vdop: None,
+            gsv_group: None,
+            gsa_group: None,
+        }
+    }
+
+    /// Feed every NMEA sentence, other sentences end a group of GSA. Groups are applied whole,
+    /// returns true if the status changed: on the last sentence of a GSV group and on the
+    /// sentence after a group of GSA.
+    pub fn update(&mut self, sentence: &str) -> bool {
+        let kind = sentence.get(3..6);
+        let mut changed = false;
+        if kind != Some("GSA")
+            && let Some(group) = self.gsa_group.take()
+        {
+            self.apply_gsa(group);
+            changed = true;
+        }
+        if !matches!(kind, Some("GSV" | "GSA")) {
+            return changed;
+        }
+        match parse_str(sentence) {
+            Ok(ParseResult::GSV(gsv)) => self.on_gsv(&gsv) || changed,
+            Ok(ParseResult::GSA(gsa)) => {
+                self.on_gsa(&gsa, gsa_constellation(sentence));
+                changed
+            }
+            _ => changed,
+        }
+    }
+
+    pub fn fix_mode(&self) -> FixMode {
+        self.fix_mode
+    }
+
+    /// Satellites in view with their SNR and whether they are used
+    pub fn satellites(&self) -> impl Iterator<Item = Satellite> + '_ {
+        self.satellites.iter().map(|satellite| Satellite {
+            used: self.is_used(satellite),
+            ..*satellite
+        })
+    }
+
+    pub fn count(&self, constellation: Constellation) -> ConstellationCount {
+        let index = constellation as usize;
+        ConstellationCount {
+            in_view: self.in_view[index],
+            used: self
+                .satellites()
+                .filter(|s| s.constellation == constellation && s.used)
+                .count() as u8,
+        }
+    }
+
+    pub fn summary(&self) -> SatelliteSummary {
+        let strong = self
+            .satellites
+            .iter()
+            .filter(|s| s.snr.is_some_and(|snr| snr >= STRONG_SNR))
+            .count() as u8;
+        SatelliteSummary {
+            constellations: Constellation::ALL.map(|c| self.count(c)),
+            strong,
+            max_snr: self.satellites.iter().filter_map(|s| s.snr).max(),
+            fix_mode: self.fix_mode,
+            pdop: self.pdop,
+            hdop: self.hdop,
+            vdop: self.vdop,
+            health: self.health(strong),
+        }
+    }
+
+    fn health(&self, strong: u8) -> GpsHealth {
+        let used = self.used.len();
+        if self.fix_mode == FixMode::NoFix || strong < 4 {
+            GpsHealth::Bad
+        } else if self.fix_mode == FixMode::Fix2d
+            || used < 6
+            || strong < 6
+            || self.pdop.is_none_or(|pdop| pdop > 3.0)
+        {
+            GpsHealth::Degraded
+        } else {
+            GpsHealth::Good
+        }
+    }
+
+    fn on_gsv(&mut self, gsv: &GsvData) -> bool {
+        let constellation = Constellation::from_gnss_type(gsv.gnss_type);
+        // the first sentence of a group starts a new list
+        if gsv.sentence_num == 1 {
+            self.gsv_group = Some(GsvGroup {
+                constellation,
+                sentences: gsv.number_of_sentences,
+                next_sentence: 1,
+                in_view: gsv.sats_in_view.min(u8::MAX as u16) as u8,
+                satellites: Vec::new(),
+            });
+        }
+        let Some(group) = self.gsv_group.as_mut() else {
+            return false;
+        };
+        // a lost sentence drops the group, the previous list stays until the next one
+        if group.constellation != constellation || group.next_sentence != gsv.sentence_num {
+            self.gsv_group = None;
+            return false;
+        }
+        group.next_sentence += 1;
+        for satellite in gsv.sats_info.iter().flatten() {
+            group
+                .satellites
+                .push(Satellite {
+                    constellation,
+                    prn: satellite.prn(),
+                    snr: satellite.snr().map(|snr| snr as u8),
+                    elevation: satellite.elevation().map(|elevation| elevation as u8),
+                    used: false,
+                })
+                .ok();
+        }
+        if gsv.sentence_num < group.sentences {
+            return false;
+        }
+
+        let Some(group) = self.gsv_group.take() else {
+            return false;
+        };
+        self.satellites.retain(|s| s.constellation != constellation);
+        for satellite in group.satellites {
+            if self.satellites.push(satellite).is_err() {
+                break;
+            }
+        }
+        self.in_view[constellation as usize] = group.in_view;
+        true
+    }
+
+    fn on_gsa(&mut self, gsa: &GsaData, constellation: Option<Constellation>) {
+        let group = self.gsa_group.get_or_insert_with(|| GsaGroup {
+            used: Vec::new(),
+            fix_mode: FixMode::NoFix,
+            pdop: None,
+            hdop: None,
+            vdop: None,
+        });
+        for &prn in gsa.fix_sats_prn.iter() {
+            group.used.push((constellation, prn)).ok();
+        }
+        group.fix_mode = match gsa.mode2 {
+            GsaMode2::NoFix => FixMode::NoFix,
+            GsaMode2::Fix2D => FixMode::Fix2d,
+            GsaMode2::Fix3D => FixMode::Fix3d,
+        };
+        group.pdop = gsa.pdop;
+        group.hdop = gsa.hdop;
+        group.vdop = gsa.vdop;
+    }
+
+    fn apply_gsa(&mut self, group: GsaGroup) {
+        self.used = group.used;
+        self.fix_mode = group.fix_mode;
+        self.pdop = group.pdop;
+        self.hdop = group.hdop;
+        self.vdop = group.vdop;
+    }
+
+    fn is_used(&self, satellite: &Satellite) -> bool {
+        self.used.iter().any(|&(constellation, prn)| {
+            prn == satellite.prn && constellation.is_none_or(|c| c == satellite.constellation)
+        })
+    }
+}
+
+/// Constellation of a GSA from its system id or talker, `None` for GNGSA before NMEA 4.10
+fn gsa_constellation(sentence: &str) -> Option<Constellation> {
+    let body = sentence.split('*').next()?;
+    // sentence type, 2 modes, 12 PRNs, 3 DOPs and the system id
+    match body.split(',').nth(18) {
+        Some(id) => Constellation::from_system_id(id),
+        None => Constellation::from_talker(sentence.get(1..3)?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::nmea_framer::nmea_checksum;
+    use core::fmt::Write as _;
+
+    fn sentence(body: &str) -> std::string::String {
+        let mut sentence = std::format!("${body}*");
+        write!(sentence, "{:02X}", nmea_checksum(body.as_bytes())).unwrap();
+        sentence
+    }
+
+    fn feed(status: &mut SatelliteStatus, bodies: &[&str]) {
+        for body in bodies {
+            status.update(&sentence(body));
+        }
+    }
+
+    #[test]
+    fn tracks_constellations_and_health() {
+        let mut status = SatelliteStatus::new();
+        feed(
+            &mut status,
+            &[
+                "GPGSV,2,1,06,02,45,100,42,05,30,200,38,12,60,050,45,15,10,300,20",
+                "GPGSV,2,2,06,24,70,120,40,29,05,010,",
+                "GLGSV,1,1,03,65,40,090,35,66,20,180,28,72,50,270,33",
+                "GPRMC,,V,,,,,,,,,,N",
+                "GNGSA,A,3,02,05,12,24,,,,,,,,,1.8,1.0,1.5,1",
+                "GNGSA,A,3,65,72,,,,,,,,,,,1.8,1.0,1.5,2",
+                "GPGGA,,,,,,1,,,,,,,,",
+            ],
+        );
+        let summary = status.summary();
+        assert_eq!(
+            summary.constellations[0],
+            ConstellationCount {
+                in_view: 6,
+                used: 4
+            }
+        );
+        assert_eq!(
+            summary.constellations[1],
+            ConstellationCount {
+                in_view: 3,
+                used: 2
+            }
+        );
+        assert_eq!(summary.strong, 6);
+        assert_eq!(summary.max_snr, Some(45));
+        assert_eq!(summary.fix_mode, FixMode::Fix3d);
+        assert_eq!(summary.pdop, Some(1.8));
+        assert_eq!(summary.health, GpsHealth::Good);
+        let prn_15 = status.satellites().find(|s| s.prn == 15).unwrap();
+        assert_eq!((prn_15.snr, prn_15.used), (Some(20), false));
+
+        // the antenna is covered, a new GSV group replaces the old list
+        feed(
+            &mut status,
+            &[
+                "GPGSV,1,1,03,02,45,100,24,12,60,050,31,24,70,120,",
+                "GLGSV,1,1,00",
+                "GPGGA,,,,,,0,,,,,,,,",
+                "GNGSA,A,1,,,,,,,,,,,,,99.9,99.9,99.9,1",
+                "GPRMC,,V,,,,,,,,,,N",
+            ],
+        );
+        let summary = status.summary();
+        assert_eq!(summary.constellations[0].in_view, 3);
+        assert_eq!(summary.constellations[1].in_view, 0);
+        assert_eq!(summary.strong, 1);
+        assert_eq!(summary.health, GpsHealth::Bad);
+    }
+
+    #[test]
+    fn matches_gsa_without_system_id() {
+        let mut status = SatelliteStatus::new();
+        // NMEA 4.0, GLONASS PRNs from 65
+        feed(
+            &mut status,
+            &[
+                "GPGSV,1,1,02,02,45,100,42,05,30,200,38",
+                "GLGSV,1,1,01,65,40,090,35",
+                "GNGSA,A,2,02,,,,,,,,,,,,2.5,2.1,1.3",
+                "GNGSA,A,2,65,,,,,,,,,,,,2.5,2.1,1.3",
+                "GPRMC,,V,,,,,,,,,,N",
+            ],
+        );
+        let used: std::vec::Vec<_> = status
+            .satellites()
+            .filter(|s| s.used)
+            .map(|s| s.prn)
+            .collect();
+        assert_eq!(used, [2, 65]);
+        assert_eq!(status.fix_mode(), FixMode::Fix2d);
+        assert_eq!(status.summary().health, GpsHealth::Bad);
+    }
+
+    #[test]
+    fn applies_whole_groups() {
+        let mut status = SatelliteStatus::new();
+        let changes = |status: &mut SatelliteStatus, bodies: &[&str]| -> std::vec::Vec<bool> {
+            bodies
+                .iter()
+                .map(|body| status.update(&sentence(body)))
+                .collect()
+        };
+        let group = [
+            "GPGSV,3,1,09,01,45,100,42,02,30,200,38,03,60,050,45,04,10,300,40",
+            "GPGSV,3,2,09,05,45,100,42,06,30,200,38,07,60,050,45,08,10,300,40",
+            "GPGSV,3,3,09,09,70,120,40",
+            "GNGSA,A,3,01,02,03,04,05,06,,,,,,,1.5,0.9,1.2,1",
+            "GNGSA,A,3,07,08,,,,,,,,,,,1.5,0.9,1.2,1",
+            "GPGGA,,,,,,1,,,,,,,,",
+        ];
+        assert_eq!(
+            changes(&mut status, &group),
+            [false, false, true, false, false, true]
+        );
+        let good = status.summary();
+        assert_eq!(good.strong, 9);
+        assert_eq!(good.constellations[0].used, 8);
+        assert_eq!(good.health, GpsHealth::Good);
+
+        // nothing changes partway through the next epoch's groups
+        for (body, changed) in group.iter().zip([false, false, true, false, false, true]) {
+            assert_eq!(status.update(&sentence(body)), changed);
+            if !changed {
+                assert_eq!(status.summary(), good);
+            }
+        }
+
+        // a group with a lost sentence is dropped
+        assert_eq!(changes(&mut status, &[group[0], group[2]]), [false, false]);
+        assert_eq!(status.summary(), good);
+    }
+}
@@ -0,0 +1,111 @@
+#![no_std]
+#![no_main]
+#![feature(impl_trait_in_assoc_type)]
+
+// Guided six position accelerometer and gyro bias calibration.
+// Follow the log prompts, the LED blinks the number of the position that is expected next
+// and stays on while samples are being collected. The result is logged as an
+// `ImuCalibration` to be pasted into the flight code. The calibration is only valid for
+// the accelerometer full scale it was taken with.
+
+use defmt::*;
+use embassy_embedded_hal::shared_bus::asynch::spi::SpiDeviceWithConfig;
+use embassy_executor::Spawner;
+use embassy_stm32::spi::{Config as SpiConfig, Spi};
+use embassy_stm32::{
+    gpio::{Level, Output, Speed},
+    time::Hertz,
+};
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
+use embassy_time::{Duration, Ticker, Timer};
+use vlf4::board::{Board, ImuBus};
+use vlf4::calibration::{AxisStats, CalibrationPosition, SixPositionCalibrator};
+use vlf4::lsm6dsm::{FifoConfig, IMUData, LSM6DSM};
+
+use {defmt_rtt as _, panic_probe as _};
+
+#[embassy_executor::main]
+async fn main(spawner: Spawner) {
+    let board = Board::init();
+    info!("Hello world");
+
+    let led = Output::new(board.status_led, Level::Low, Speed::Low);
+
+    spawner.spawn(calibration_task(board.imu, led).unwrap());
+}
+
+#[embassy_executor::task]
+async fn calibration_task(imu_bus: ImuBus, mut led: Output<'static>) {
+    let mut spi_config = SpiConfig::default();
+    spi_config.frequency = Hertz(1_000_000);
+    let spi = Mutex::<NoopRawMutex, _>::new(Spi::new(
+        imu_bus.spi,
+        imu_bus.sck,
+        imu_bus.mosi,
+        imu_bus.miso,
+        imu_bus.tx_dma,
+        imu_bus.rx_dma,
+        spi_config,
+    ));
+    let cs = Output::new(imu_bus.cs, Level::High, Speed::High);
+    let spi_device = SpiDeviceWithConfig::new(&spi, cs, spi_config);
+    let mut imu = LSM6DSM::new(spi_device);
+    unwrap!(imu.reset().await);
+    unwrap!(imu.configure_fifo(FifoConfig::default()).await);
+    info!("Calibrating with {}", imu.config());
+
+    let mut calibrator = SixPositionCalibrator::new();
+    let mut samples = [IMUData::default(); 64];
+    for (i, position) in CalibrationPosition::ALL.into_iter().enumerate() {
+        info!(
+            "Position {}/6: place the board with {} and hold it still",
+            i + 1,
+            position
+        );
+        for _ in 0..=i {
+            led.set_high();
+            Timer::after_millis(150).await;
+            led.set_low();
+            Timer::after_millis(150).await;
+        }
+
+        let mut ticker = Ticker::every(Duration::from_millis(100));
+        while calibrator.samples(position) < calibrator.min_samples {
+            ticker.next().await;
+            let sample_count = match imu.read_fifo(&mut samples).await {
+                Ok(sample_count) => sample_count,
+                Err(e) => {
+                    warn!("IMU read error: {}", e);
+                    continue;
+                }
+            };
+            let batch = &samples[..sample_count];
+
+            let mut batch_stats = AxisStats::default();
+            for sample in batch {
+                batch_stats.add(&sample.acc);
+            }
+            let in_position =
+                CalibrationPosition::detect(&batch_stats.mean(), 2.0) == Some(position);
+            let still = batch_stats.max_variance()
+                < calibrator.max_acc_std_dev * calibrator.max_acc_std_dev;
+            if in_position && still {
+                led.set_high();
+                for sample in batch {
+                    calibrator.add(position, sample);
+                }
+            } else {
+                // start over if the board moves half way through
+                led.set_low();
+                calibrator.clear(position);
+            }
+        }
+        led.set_low();
+        info!("Position {}/6 done", i + 1);
+    }
+
+    match calibrator.solve(true) {
+        Ok(calibration) => info!("Calibration: {}", calibration),
+        Err(e) => error!("Calibration failed: {}", e),
+    }
+}
@@ -0,0 +1,48 @@
+#![cfg_attr(not(test), no_std)]
+
+// Code shared by the binaries. Everything except `board` and `clock` is hardware independent,
+// written against the embedded-hal-async / embedded-io-async traits, and builds on the host.
+// Run the unit tests with `cargo test-host`.
+
+pub mod attitude;
+#[cfg(target_os = "none")]
+pub mod board;
+pub mod calibration;
+#[cfg(target_os = "none")]
+pub mod clock;
+pub mod clock_tree;
+pub mod gps_config;
+pub mod gps_demux;
+pub mod gps_fix;
+pub mod gps_satellites;
+pub mod lsm6dsm;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+pub mod nmea_framer;
+pub mod orientation;
+pub mod pps_association;
+pub mod pps_capture;
+pub mod revision;
+pub mod tilt_trigger;
+pub mod time_discipline;
+pub mod timing;
+pub mod ubx;
+
+// the host has no defmt transport, logging from tests goes nowhere
+#[cfg(test)]
+mod defmt_stub {
+    #[defmt::global_logger]
+    struct Logger;
+
+    unsafe impl defmt::Logger for Logger {
+        fn acquire() {}
+        unsafe fn flush() {}
+        unsafe fn release() {}
+        unsafe fn write(_bytes: &[u8]) {}
+    }
+
+    #[defmt::panic_handler]
+    fn panic() -> ! {
+        panic!("defmt panic")
+    }
+}
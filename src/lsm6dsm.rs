@@ -1,103 +1,1423 @@
-use embassy_time::Timer;
+use crate::calibration::ImuCalibration;
+use crate::orientation::MountingOrientation;
+use core::convert::Infallible;
+use core::fmt::Debug;
+use embassy_time::{Duration, Instant, Timer};
+use embedded_hal_async::digital::Wait;
 use embedded_hal_async::spi::SpiDevice;
+use nalgebra::{Matrix3, Vector3};
 
+const FIFO_CTRL1: u8 = 0x06;
+const FIFO_CTRL2: u8 = 0x07;
+const FIFO_CTRL3: u8 = 0x08;
+const FIFO_CTRL4: u8 = 0x09;
+const FIFO_CTRL5: u8 = 0x0A;
+const DRDY_PULSE_CFG: u8 = 0x0B;
+const INT1_CTRL: u8 = 0x0D;
+const INT2_CTRL: u8 = 0x0E;
 const WHO_AM_I: u8 = 0x0F;
 const CTRL1_XL: u8 = 0x10;
 const CTRL2_G: u8 = 0x11;
 const CTRL3_C: u8 = 0x12;
 const CTRL4_C: u8 = 0x13;
+const CTRL5_C: u8 = 0x14;
 const CTRL6_C: u8 = 0x15;
+const CTRL7_G: u8 = 0x16;
+const CTRL8_XL: u8 = 0x17;
+const CTRL9_XL: u8 = 0x18;
+const CTRL10_C: u8 = 0x19;
+const STATUS_REG: u8 = 0x1E;
+const OUT_TEMP_L: u8 = 0x20;
 const OUTX_L_G: u8 = 0x22;
+const OUTX_L_XL: u8 = 0x28;
+const TIMESTAMP0_REG: u8 = 0x40;
+const TIMESTAMP2_REG: u8 = 0x42;
+const FIFO_STATUS1: u8 = 0x3A;
+const FIFO_DATA_OUT_L: u8 = 0x3E;
+const TAP_CFG: u8 = 0x58;
+const WAKE_UP_THS: u8 = 0x5B;
+const WAKE_UP_DUR: u8 = 0x5C;
+const MD1_CFG: u8 = 0x5E;
+const MD2_CFG: u8 = 0x5F;
+
+const LSM6DSM_ID: u8 = 0x6A;
+/// m/s^2 per g
+pub const STANDARD_GRAVITY: f32 = 9.81;
+// max FIFO words read per SPI transaction
+const FIFO_BURST_WORDS: usize = 96;
+// nominal timestamp counter resolution with TIMER_HR set
+const TIMESTAMP_TICK_US: f32 = 25.0;
+// the 24 bit timestamp counter wraps every ~7 minutes, and the chip oscillator drifts
+// against the MCU clock, so the mapping to `Instant` is refreshed this often
+const TIMESTAMP_SYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+pub enum LSM6DSMError<E> {
+    /// The SPI transfer itself failed
+    Bus(E),
+    /// WHO_AM_I did not match, contains the value read
+    WrongChipId(u8),
+    /// The chip did not finish rebooting after a software reset
+    BootTimeout,
+    /// Neither the accelerometer nor the gyroscope had a new sample
+    DataNotReady,
+    SelfTestFailed(SelfTestReport),
+}
+
+impl<E: Debug> defmt::Format for LSM6DSMError<E> {
+    fn format(&self, f: defmt::Formatter) {
+        match self {
+            LSM6DSMError::Bus(e) => defmt::write!(f, "Bus({})", defmt::Debug2Format(e)),
+            LSM6DSMError::WrongChipId(id) => defmt::write!(f, "WrongChipId({=u8:#x})", id),
+            LSM6DSMError::BootTimeout => defmt::write!(f, "BootTimeout"),
+            LSM6DSMError::DataNotReady => defmt::write!(f, "DataNotReady"),
+            LSM6DSMError::SelfTestFailed(report) => {
+                defmt::write!(f, "SelfTestFailed({})", report)
+            }
+        }
+    }
+}
+
+/// Output data rate, shared by the accelerometer (ODR_XL) and gyroscope (ODR_G)
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputDataRate {
+    PowerDown = 0b0000,
+    Hz12_5 = 0b0001,
+    Hz26 = 0b0010,
+    Hz52 = 0b0011,
+    Hz104 = 0b0100,
+    Hz208 = 0b0101,
+    Hz416 = 0b0110,
+    Hz833 = 0b0111,
+    Hz1660 = 0b1000,
+    Hz3330 = 0b1001,
+    Hz6660 = 0b1010,
+}
+
+impl OutputDataRate {
+    pub fn hz(&self) -> f32 {
+        match self {
+            OutputDataRate::PowerDown => 0.0,
+            OutputDataRate::Hz12_5 => 12.5,
+            OutputDataRate::Hz26 => 26.0,
+            OutputDataRate::Hz52 => 52.0,
+            OutputDataRate::Hz104 => 104.0,
+            OutputDataRate::Hz208 => 208.0,
+            OutputDataRate::Hz416 => 416.0,
+            OutputDataRate::Hz833 => 833.0,
+            OutputDataRate::Hz1660 => 1660.0,
+            OutputDataRate::Hz3330 => 3330.0,
+            OutputDataRate::Hz6660 => 6660.0,
+        }
+    }
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccFullScale {
+    G2,
+    G4,
+    G8,
+    G16,
+}
+
+impl AccFullScale {
+    fn bits(&self) -> u8 {
+        match self {
+            AccFullScale::G2 => 0b00,
+            AccFullScale::G4 => 0b10,
+            AccFullScale::G8 => 0b11,
+            AccFullScale::G16 => 0b01,
+        }
+    }
+
+    /// m/s^2 per LSB
+    pub fn sensitivity(&self) -> f32 {
+        let mg_per_lsb = match self {
+            AccFullScale::G2 => 0.061,
+            AccFullScale::G4 => 0.122,
+            AccFullScale::G8 => 0.244,
+            AccFullScale::G16 => 0.488,
+        };
+        mg_per_lsb / 1000.0 * STANDARD_GRAVITY
+    }
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GyroFullScale {
+    Dps125,
+    Dps250,
+    Dps500,
+    Dps1000,
+    Dps2000,
+}
+
+impl GyroFullScale {
+    // FS_G[1:0] and FS_125 bits of CTRL2_G
+    fn bits(&self) -> u8 {
+        match self {
+            GyroFullScale::Dps125 => 0b001,
+            GyroFullScale::Dps250 => 0b000,
+            GyroFullScale::Dps500 => 0b010,
+            GyroFullScale::Dps1000 => 0b100,
+            GyroFullScale::Dps2000 => 0b110,
+        }
+    }
+
+    /// deg/s per LSB
+    pub fn sensitivity(&self) -> f32 {
+        let mdps_per_lsb = match self {
+            GyroFullScale::Dps125 => 4.375,
+            GyroFullScale::Dps250 => 8.75,
+            GyroFullScale::Dps500 => 17.5,
+            GyroFullScale::Dps1000 => 35.0,
+            GyroFullScale::Dps2000 => 70.0,
+        };
+        mdps_per_lsb / 1000.0
+    }
+}
+
+/// Accelerometer anti-aliasing filter bandwidth (BW0_XL)
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccAnalogBandwidth {
+    Hz1500,
+    Hz400,
+}
+
+/// Accelerometer digital low pass filter, cutoff relative to the accelerometer ODR
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccLowPass {
+    // LPF1 only
+    OdrDiv2,
+    OdrDiv4,
+    // LPF1 + LPF2
+    OdrDiv9,
+    OdrDiv50,
+    OdrDiv100,
+    OdrDiv400,
+}
+
+/// Gyroscope LPF1 bandwidth (FTYPE), the exact cutoff depends on the gyro ODR
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GyroLowPass {
+    Disabled,
+    Wide,
+    Normal,
+    Narrow,
+    VeryNarrow,
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FifoMode {
+    /// FIFO disabled and cleared
+    Bypass = 0b000,
+    /// Stops collecting data when full
+    Fifo = 0b001,
+    /// Continuous until a trigger event, then FIFO mode
+    ContinuousToFifo = 0b011,
+    /// Bypass until a trigger event, then continuous mode
+    BypassToContinuous = 0b100,
+    /// Overwrites the oldest data when full
+    Continuous = 0b110,
+}
+
+/// How often a sensor's samples are stored into the FIFO, relative to the FIFO ODR
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FifoDecimation {
+    NotInFifo = 0b000,
+    NoDecimation = 0b001,
+    Div2 = 0b010,
+    Div3 = 0b011,
+    Div4 = 0b100,
+    Div8 = 0b101,
+    Div16 = 0b110,
+    Div32 = 0b111,
+}
+
+impl FifoDecimation {
+    fn factor(&self) -> u16 {
+        match self {
+            FifoDecimation::NotInFifo => 0,
+            FifoDecimation::NoDecimation => 1,
+            FifoDecimation::Div2 => 2,
+            FifoDecimation::Div3 => 3,
+            FifoDecimation::Div4 => 4,
+            FifoDecimation::Div8 => 8,
+            FifoDecimation::Div16 => 16,
+            FifoDecimation::Div32 => 32,
+        }
+    }
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq)]
+pub struct FifoConfig {
+    pub mode: FifoMode,
+    /// Should be at least the highest ODR of the sensors stored in the FIFO
+    pub odr: OutputDataRate,
+    /// In 16 bit FIFO words, one sensor sample is 3 words
+    pub watermark: u16,
+    pub gyro_decimation: FifoDecimation,
+    pub acc_decimation: FifoDecimation,
+    /// Should match the least decimated sensor so every sample gets its own timestamp,
+    /// otherwise the timestamp is extrapolated from the previous one at the FIFO ODR
+    pub timestamp_decimation: FifoDecimation,
+}
+
+impl Default for FifoConfig {
+    fn default() -> Self {
+        Self {
+            mode: FifoMode::Continuous,
+            odr: OutputDataRate::Hz416,
+            // 32 steps of gyro, acc and timestamp, 3 words each
+            watermark: 32 * 9,
+            gyro_decimation: FifoDecimation::NoDecimation,
+            acc_decimation: FifoDecimation::NoDecimation,
+            timestamp_decimation: FifoDecimation::NoDecimation,
+        }
+    }
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy)]
+pub struct FifoStatus {
+    /// Number of unread 16 bit words
+    pub unread_words: u16,
+    pub watermark_reached: bool,
+    pub overrun: bool,
+    pub full: bool,
+    pub empty: bool,
+    /// Index of the next word to be read within the FIFO pattern
+    pub pattern: u16,
+}
+
+/// Events routed to one of the interrupt pins, the pin is high while any of them is active
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct InterruptRouting {
+    pub acc_data_ready: bool,
+    pub gyro_data_ready: bool,
+    pub fifo_watermark: bool,
+    pub fifo_overrun: bool,
+    pub fifo_full: bool,
+    pub wake_up: bool,
+}
+
+impl InterruptRouting {
+    // INT1_CTRL and INT2_CTRL share the bit positions of these events
+    fn ctrl_bits(&self) -> u8 {
+        (self.fifo_full as u8) << 5
+            | (self.fifo_overrun as u8) << 4
+            | (self.fifo_watermark as u8) << 3
+            | (self.gyro_data_ready as u8) << 1
+            | self.acc_data_ready as u8
+    }
+
+    // INT1_WU in MD1_CFG, INT2_WU in MD2_CFG
+    fn md_cfg_bits(&self) -> u8 {
+        (self.wake_up as u8) << 5
+    }
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Default)]
+pub struct InterruptConfig {
+    pub int1: InterruptRouting,
+    pub int2: InterruptRouting,
+    /// Pulse data ready for 75us instead of latching it until the data is read
+    pub pulsed_data_ready: bool,
+    /// High-pass filtered acceleration on any axis above this wakes up, in m/s^2,
+    /// resolution is 1/64 of the accelerometer full scale
+    pub wake_up_threshold: f32,
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq)]
+pub struct LSM6DSMConfig {
+    pub acc_odr: OutputDataRate,
+    pub acc_full_scale: AccFullScale,
+    pub acc_analog_bandwidth: AccAnalogBandwidth,
+    pub acc_low_pass: AccLowPass,
+    pub gyro_odr: OutputDataRate,
+    pub gyro_full_scale: GyroFullScale,
+    pub gyro_low_pass: GyroLowPass,
+}
+
+impl Default for LSM6DSMConfig {
+    fn default() -> Self {
+        Self {
+            acc_odr: OutputDataRate::Hz416,
+            acc_full_scale: AccFullScale::G16,
+            acc_analog_bandwidth: AccAnalogBandwidth::Hz1500,
+            acc_low_pass: AccLowPass::OdrDiv4,
+            gyro_odr: OutputDataRate::Hz416,
+            gyro_full_scale: GyroFullScale::Dps2000,
+            gyro_low_pass: GyroLowPass::VeryNarrow,
+        }
+    }
+}
 
 pub struct LSM6DSM<B: SpiDevice> {
     spi: B,
+    config: LSM6DSMConfig,
+    fifo_config: FifoConfig,
+    interrupt_config: InterruptConfig,
+    // most recent raw samples read from the FIFO, used to fill in the sensor
+    // that has no data in a pattern step when decimations differ
+    fifo_last_gyro: [i16; 3],
+    fifo_last_acc: [i16; 3],
+    fifo_last_timestamp: Instant,
+    timestamp_mapper: TimestampMapper,
+    gyro_temperature_compensation: Option<GyroTemperatureCompensation>,
+    calibration: Option<ImuCalibration>,
+    mounting_orientation: MountingOrientation,
 }
 
 impl<B: SpiDevice> LSM6DSM<B> {
     pub fn new(spi_device: B) -> Self {
-        Self { spi: spi_device }
+        Self::new_with_config(spi_device, LSM6DSMConfig::default())
     }
 
-    async fn read_register(&mut self, address: u8) -> Result<u8, B::Error> {
+    /// `config` is applied on the next `reset`
+    pub fn new_with_config(spi_device: B, config: LSM6DSMConfig) -> Self {
+        Self {
+            spi: spi_device,
+            config,
+            fifo_config: FifoConfig {
+                mode: FifoMode::Bypass,
+                ..FifoConfig::default()
+            },
+            interrupt_config: InterruptConfig::default(),
+            fifo_last_gyro: [0; 3],
+            fifo_last_acc: [0; 3],
+            fifo_last_timestamp: Instant::from_ticks(0),
+            timestamp_mapper: TimestampMapper::new(),
+            gyro_temperature_compensation: None,
+            calibration: None,
+            mounting_orientation: MountingOrientation::IDENTITY,
+        }
+    }
+
+    pub fn config(&self) -> &LSM6DSMConfig {
+        &self.config
+    }
+
+    /// Subtracts the modelled gyro bias from every sample read from now on
+    pub fn set_gyro_temperature_compensation(
+        &mut self,
+        compensation: Option<GyroTemperatureCompensation>,
+    ) {
+        self.gyro_temperature_compensation = compensation;
+    }
+
+    /// Applied to every sample read from now on, after the temperature compensation.
+    /// Must be `None` while collecting samples for a new calibration.
+    pub fn set_calibration(&mut self, calibration: Option<ImuCalibration>) {
+        self.calibration = calibration;
+    }
+
+    /// Rotates every sample read from now on into the vehicle frame, after calibration
+    pub fn set_mounting_orientation(&mut self, mounting_orientation: MountingOrientation) {
+        self.mounting_orientation = mounting_orientation;
+    }
+
+    async fn read_register(&mut self, address: u8) -> Result<u8, LSM6DSMError<B::Error>> {
         let mut buffer = [0u8; 2];
 
         self.spi
             .transfer(&mut buffer, &[address | 0b10000000, 0x00])
-            .await?;
+            .await
+            .map_err(LSM6DSMError::Bus)?;
 
         Ok(buffer[1])
     }
 
-    async fn write_register(&mut self, address: u8, value: u8) -> Result<(), B::Error> {
+    async fn write_register(
+        &mut self,
+        address: u8,
+        value: u8,
+    ) -> Result<(), LSM6DSMError<B::Error>> {
         self.spi
             .transfer(&mut [0u8; 2], &[address & !0b10000000, value])
-            .await?;
+            .await
+            .map_err(LSM6DSMError::Bus)?;
         Ok(())
     }
 
-    pub async fn reset(&mut self) -> Result<bool, B::Error> {
-        // reset
+    async fn modify_register(
+        &mut self,
+        address: u8,
+        mask: u8,
+        value: u8,
+    ) -> Result<(), LSM6DSMError<B::Error>> {
+        let current = self.read_register(address).await?;
+        self.write_register(address, (current & !mask) | (value & mask))
+            .await
+    }
+
+    pub async fn reset(&mut self) -> Result<(), LSM6DSMError<B::Error>> {
+        // reboot memory content and software reset
         self.write_register(CTRL3_C, 0b10000101).await?;
-        Timer::after_millis(20).await; // wait for initialize
+
+        // BOOT and SW_RESET clear themselves once the chip is initialized
+        let deadline = Instant::now() + Duration::from_millis(100);
+        loop {
+            Timer::after_millis(5).await;
+            if self.read_register(CTRL3_C).await? & 0b10000001 == 0 {
+                break;
+            }
+            if Instant::now() > deadline {
+                return Err(LSM6DSMError::BootTimeout);
+            }
+        }
 
         let id = self.read_register(WHO_AM_I).await?;
-        if id != 0x6A {
-            return Ok(false);
+        if id != LSM6DSM_ID {
+            return Err(LSM6DSMError::WrongChipId(id));
         }
 
         // enable block data update
         self.write_register(CTRL3_C, 0b01000100).await?;
-        // set acc ODR to 416Hz, bandwidth 104Hz, full scale to +-16g
-        self.write_register(CTRL1_XL, 0b0110_01_1_0).await?;
-        // set gyro ODR to 416Hz, full scale to +-2000dps
-        self.write_register(CTRL2_G, 0b0110_11_0_0).await?;
-        // enable gyro filter
-        self.write_register(CTRL4_C, 0b0000_0001).await?;
-        // gyro bandwidth 121Hz
-        self.write_register(CTRL6_C, 0b0000_0010).await?;
+        self.configure(self.config).await?;
+
+        // enable the timestamp counter with 25us resolution (TIMER_HR) and reset it
+        self.modify_register(WAKE_UP_DUR, 0b0001_0000, 0b0001_0000)
+            .await?;
+        self.modify_register(CTRL10_C, 0b0010_0100, 0b0010_0100)
+            .await?;
+        self.write_register(TIMESTAMP2_REG, 0xAA).await?;
+        self.timestamp_mapper = TimestampMapper::new();
+        self.sync_timestamp().await?;
 
         Timer::after_millis(1).await;
-        Ok(true)
+        Ok(())
     }
 
-    pub async fn read(&mut self) -> Result<IMUData, B::Error> {
-        let mut buffer = [0u8; 13];
+    /// Applies `config` to the sensor, can be called at any time after `reset`
+    pub async fn configure(&mut self, config: LSM6DSMConfig) -> Result<(), LSM6DSMError<B::Error>> {
+        let (lpf1_bw_sel, lpf2_xl_en, hpcf_xl) = match config.acc_low_pass {
+            AccLowPass::OdrDiv2 => (0, 0, 0b00),
+            AccLowPass::OdrDiv4 => (1, 0, 0b00),
+            AccLowPass::OdrDiv9 => (0, 1, 0b10),
+            AccLowPass::OdrDiv50 => (0, 1, 0b00),
+            AccLowPass::OdrDiv100 => (0, 1, 0b01),
+            AccLowPass::OdrDiv400 => (0, 1, 0b11),
+        };
+        let bw0_xl = match config.acc_analog_bandwidth {
+            AccAnalogBandwidth::Hz1500 => 0,
+            AccAnalogBandwidth::Hz400 => 1,
+        };
+        self.write_register(
+            CTRL1_XL,
+            (config.acc_odr as u8) << 4
+                | config.acc_full_scale.bits() << 2
+                | lpf1_bw_sel << 1
+                | bw0_xl,
+        )
+        .await?;
+        self.write_register(CTRL8_XL, lpf2_xl_en << 7 | hpcf_xl << 5)
+            .await?;
+
+        self.write_register(
+            CTRL2_G,
+            (config.gyro_odr as u8) << 4 | config.gyro_full_scale.bits() << 1,
+        )
+        .await?;
+        let (lpf1_sel_g, ftype) = match config.gyro_low_pass {
+            GyroLowPass::Disabled => (0, 0b00),
+            GyroLowPass::Wide => (1, 0b11),
+            GyroLowPass::Normal => (1, 0b00),
+            GyroLowPass::Narrow => (1, 0b01),
+            GyroLowPass::VeryNarrow => (1, 0b10),
+        };
+        self.modify_register(CTRL4_C, 0b0000_0010, lpf1_sel_g << 1)
+            .await?;
+        self.modify_register(CTRL6_C, 0b0000_0011, ftype).await?;
+
+        self.config = config;
+        Ok(())
+    }
+
+    pub async fn read(&mut self) -> Result<IMUData, LSM6DSMError<B::Error>> {
+        // STATUS_REG, 1 reserved byte, OUT_TEMP_L/H, then gyro and acc output
+        let mut buffer = [0u8; 17];
+        let mut command = [0u8; 17];
+        command[0] = STATUS_REG | 0x80;
+        self.spi
+            .transfer(&mut buffer, &command)
+            .await
+            .map_err(LSM6DSMError::Bus)?;
+
+        // XLDA or GDA
+        if buffer[1] & 0b011 == 0 {
+            return Err(LSM6DSMError::DataNotReady);
+        }
+
+        let temperature = i16::from_le_bytes([buffer[3], buffer[4]]);
+        let buffer = &buffer[5..];
+        let mut words = [0i16; 6];
+        for (i, word) in words.iter_mut().enumerate() {
+            *word = i16::from_le_bytes([buffer[i * 2], buffer[i * 2 + 1]]);
+        }
+
+        // the output registers are not timestamped, the counter read right after them is
+        // within one ODR period of the sample
+        let timestamp = self.sync_timestamp().await?;
+
+        Ok(self.convert(
+            &[words[0], words[1], words[2]],
+            &[words[3], words[4], words[5]],
+            temperature,
+            timestamp,
+        ))
+    }
+
+    fn convert(
+        &self,
+        gyro: &[i16; 3],
+        acc: &[i16; 3],
+        temperature: i16,
+        timestamp: Instant,
+    ) -> IMUData {
+        let acc_scale = self.config.acc_full_scale.sensitivity();
+        let gyro_scale = self.config.gyro_full_scale.sensitivity();
+
+        let mut data = IMUData {
+            acc: acc.map(|v| v as f32 * acc_scale),
+            gyro: gyro.map(|v| v as f32 * gyro_scale),
+            temperature: convert_temperature(temperature),
+            timestamp,
+        };
+        if let Some(compensation) = &self.gyro_temperature_compensation {
+            compensation.apply(&mut data);
+        }
+        if let Some(calibration) = &self.calibration {
+            calibration.apply(&mut data);
+        }
+        self.mounting_orientation.apply(&mut data);
+        data
+    }
+
+    /// In °C
+    pub async fn read_temperature(&mut self) -> Result<f32, LSM6DSMError<B::Error>> {
+        Ok(convert_temperature(self.read_raw_temperature().await?))
+    }
+
+    async fn read_raw_temperature(&mut self) -> Result<i16, LSM6DSMError<B::Error>> {
+        let mut buffer = [0u8; 3];
         self.spi
-            .transfer(
-                &mut buffer,
-                &[OUTX_L_G | 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
-            )
+            .transfer(&mut buffer, &[OUT_TEMP_L | 0x80, 0, 0])
+            .await
+            .map_err(LSM6DSMError::Bus)?;
+        Ok(i16::from_le_bytes([buffer[1], buffer[2]]))
+    }
+
+    /// Reads the timestamp counter and uses it to update the mapping from the chip's
+    /// clock to `Instant`, returns the current time
+    pub async fn sync_timestamp(&mut self) -> Result<Instant, LSM6DSMError<B::Error>> {
+        let mut buffer = [0u8; 4];
+        let before = Instant::now();
+        self.spi
+            .transfer(&mut buffer, &[TIMESTAMP0_REG | 0x80, 0, 0, 0])
+            .await
+            .map_err(LSM6DSMError::Bus)?;
+        let after = Instant::now();
+
+        let raw = u32::from_le_bytes([buffer[1], buffer[2], buffer[3], 0]);
+        let instant = before + (after - before) / 2;
+        self.timestamp_mapper.sync(raw, instant);
+        Ok(instant)
+    }
+
+    pub fn timestamp_mapper(&self) -> &TimestampMapper {
+        &self.timestamp_mapper
+    }
+
+    /// Configures and resets the FIFO, any data already in the FIFO is discarded
+    pub async fn configure_fifo(
+        &mut self,
+        fifo_config: FifoConfig,
+    ) -> Result<(), LSM6DSMError<B::Error>> {
+        // switching to bypass mode clears the FIFO
+        self.write_register(FIFO_CTRL5, FifoMode::Bypass as u8)
+            .await?;
+
+        let watermark = fifo_config.watermark.min(0x7FF);
+        self.write_register(FIFO_CTRL1, watermark as u8).await?;
+        // TIMER_PEDO_FIFO_EN stores the timestamp as the 4th FIFO data set
+        let timestamp_in_fifo = fifo_config.timestamp_decimation != FifoDecimation::NotInFifo;
+        self.write_register(
+            FIFO_CTRL2,
+            (timestamp_in_fifo as u8) << 7 | (watermark >> 8) as u8,
+        )
+        .await?;
+        self.write_register(
+            FIFO_CTRL3,
+            (fifo_config.gyro_decimation as u8) << 3 | fifo_config.acc_decimation as u8,
+        )
+        .await?;
+        self.write_register(FIFO_CTRL4, (fifo_config.timestamp_decimation as u8) << 3)
+            .await?;
+        self.write_register(
+            FIFO_CTRL5,
+            (fifo_config.odr as u8) << 3 | fifo_config.mode as u8,
+        )
+        .await?;
+
+        self.fifo_config = fifo_config;
+        self.fifo_last_gyro = [0; 3];
+        self.fifo_last_acc = [0; 3];
+        self.fifo_last_timestamp = Instant::now();
+        Ok(())
+    }
+
+    pub async fn fifo_status(&mut self) -> Result<FifoStatus, LSM6DSMError<B::Error>> {
+        let mut buffer = [0u8; 5];
+        self.spi
+            .transfer(&mut buffer, &[FIFO_STATUS1 | 0x80, 0, 0, 0, 0])
+            .await
+            .map_err(LSM6DSMError::Bus)?;
+
+        Ok(FifoStatus {
+            unread_words: u16::from_le_bytes([buffer[1], buffer[2] & 0b111]),
+            watermark_reached: buffer[2] & 0b1000_0000 != 0,
+            overrun: buffer[2] & 0b0100_0000 != 0,
+            full: buffer[2] & 0b0010_0000 != 0,
+            empty: buffer[2] & 0b0001_0000 != 0,
+            pattern: u16::from_le_bytes([buffer[3], buffer[4] & 0b11]),
+        })
+    }
+
+    /// Reads as many complete samples from the FIFO as fit into `samples` and returns
+    /// how many were written. Each FIFO pattern step becomes one `IMUData`, when a sensor
+    /// is decimated its most recent sample is repeated in the steps it is absent from.
+    pub async fn read_fifo(
+        &mut self,
+        samples: &mut [IMUData],
+    ) -> Result<usize, LSM6DSMError<B::Error>> {
+        let pattern = FifoPattern::new(&self.fifo_config);
+        if pattern.len_words() == 0 {
+            return Ok(0);
+        }
+
+        if self.timestamp_mapper.is_stale() {
+            self.sync_timestamp().await?;
+        }
+        // the temperature is not stored in the FIFO, the current one is used for the batch
+        let temperature = self.read_raw_temperature().await?;
+
+        let status = self.fifo_status().await?;
+        let mut unread_words = status.unread_words as usize;
+        let mut position = pattern.locate(status.pattern);
+        let mut sample_count = 0;
+        let step_period =
+            Duration::from_micros((1_000_000.0 / self.fifo_config.odr.hz().max(1.0)) as u64);
+        let mut previous_step_gap = 1;
+
+        // after an overrun or a partial read the FIFO may not start on a step boundary,
+        // drop the words of the incomplete step
+        if position.word != 0 {
+            let skip = pattern.step_words(position.step) - position.word;
+            if unread_words < skip {
+                return Ok(0);
+            }
+            let mut burst = [0u8; 1 + FIFO_BURST_WORDS * 2];
+            self.read_fifo_words(&mut burst[..1 + skip * 2]).await?;
+            unread_words -= skip;
+            position = FifoPosition {
+                step: pattern.next_step(position.step),
+                word: 0,
+            };
+        }
+
+        while sample_count < samples.len() {
+            // collect as many whole steps as fit into one burst
+            let mut step = position.step;
+            let mut burst_words = 0;
+            let mut burst_steps = 0;
+            while sample_count + burst_steps < samples.len() {
+                let step_words = pattern.step_words(step);
+                if burst_words + step_words > unread_words.min(FIFO_BURST_WORDS) {
+                    break;
+                }
+                burst_words += step_words;
+                burst_steps += 1;
+                step = pattern.next_step(step);
+            }
+            if burst_steps == 0 {
+                break;
+            }
+
+            let mut burst = [0u8; 1 + FIFO_BURST_WORDS * 2];
+            self.read_fifo_words(&mut burst[..1 + burst_words * 2])
+                .await?;
+
+            let mut words = burst[1..1 + burst_words * 2]
+                .chunks_exact(2)
+                .map(|word| i16::from_le_bytes([word[0], word[1]]));
+            let mut read_axes = || {
+                [
+                    words.next().unwrap_or(0),
+                    words.next().unwrap_or(0),
+                    words.next().unwrap_or(0),
+                ]
+            };
+            for _ in 0..burst_steps {
+                let [has_gyro, has_acc, has_timestamp] = pattern.step_sets(position.step);
+                if has_gyro {
+                    self.fifo_last_gyro = read_axes();
+                }
+                if has_acc {
+                    self.fifo_last_acc = read_axes();
+                }
+                if has_timestamp {
+                    // TIMESTAMP[15:8], TIMESTAMP[23:16], unused, TIMESTAMP[7:0], step count
+                    let data_set = read_axes();
+                    let [ts_mid, ts_high] = data_set[0].to_le_bytes();
+                    let [_, ts_low] = data_set[1].to_le_bytes();
+                    let raw = u32::from_le_bytes([ts_low, ts_mid, ts_high, 0]);
+                    self.fifo_last_timestamp = self.timestamp_mapper.to_instant(raw);
+                } else {
+                    self.fifo_last_timestamp += step_period * previous_step_gap as u32;
+                }
+                samples[sample_count] = self.convert(
+                    &self.fifo_last_gyro,
+                    &self.fifo_last_acc,
+                    temperature,
+                    self.fifo_last_timestamp,
+                );
+                sample_count += 1;
+                previous_step_gap = pattern.step_gap(position.step);
+                position.step = pattern.next_step(position.step);
+            }
+            unread_words -= burst_words;
+        }
+
+        Ok(sample_count)
+    }
+
+    // `buffer` is 1 command byte followed by the words to read
+    async fn read_fifo_words(&mut self, buffer: &mut [u8]) -> Result<(), LSM6DSMError<B::Error>> {
+        // the register address wraps from FIFO_DATA_OUT_H back to FIFO_DATA_OUT_L,
+        // so the whole FIFO can be drained in a single transaction
+        let mut command = [0u8; 1 + FIFO_BURST_WORDS * 2];
+        command[0] = FIFO_DATA_OUT_L | 0x80;
+        self.spi
+            .transfer(buffer, &command[..buffer.len()])
+            .await
+            .map_err(LSM6DSMError::Bus)
+    }
+
+    pub async fn configure_interrupts(
+        &mut self,
+        interrupt_config: InterruptConfig,
+    ) -> Result<(), LSM6DSMError<B::Error>> {
+        let full_scale_g = match self.config.acc_full_scale {
+            AccFullScale::G2 => 2.0,
+            AccFullScale::G4 => 4.0,
+            AccFullScale::G8 => 8.0,
+            AccFullScale::G16 => 16.0,
+        };
+        let threshold_lsb = full_scale_g * STANDARD_GRAVITY / 64.0;
+        let threshold = (interrupt_config.wake_up_threshold / threshold_lsb).clamp(0.0, 63.0) as u8;
+        self.modify_register(WAKE_UP_THS, 0b0011_1111, threshold)
+            .await?;
+
+        let wake_up = interrupt_config.int1.wake_up || interrupt_config.int2.wake_up;
+        // INTERRUPTS_ENABLE is needed for the embedded functions such as wake up
+        self.modify_register(TAP_CFG, 0b1000_0000, (wake_up as u8) << 7)
+            .await?;
+        self.modify_register(
+            DRDY_PULSE_CFG,
+            0b1000_0000,
+            (interrupt_config.pulsed_data_ready as u8) << 7,
+        )
+        .await?;
+
+        self.modify_register(INT1_CTRL, 0b0011_1011, interrupt_config.int1.ctrl_bits())
+            .await?;
+        self.modify_register(INT2_CTRL, 0b0011_1011, interrupt_config.int2.ctrl_bits())
+            .await?;
+        self.modify_register(MD1_CFG, 0b0010_0000, interrupt_config.int1.md_cfg_bits())
             .await?;
+        self.modify_register(MD2_CFG, 0b0010_0000, interrupt_config.int2.md_cfg_bits())
+            .await?;
+
+        self.interrupt_config = interrupt_config;
+        Ok(())
+    }
+
+    /// Waits until an event routed to the interrupt pin connected to `int_pin` is active.
+    /// Latched data ready and FIFO watermark stay high until the data is read, so this
+    /// returns immediately if data is already waiting.
+    pub async fn wait_for_data<P: Wait<Error = Infallible>>(&self, int_pin: &mut P) {
+        let Ok(()) = int_pin.wait_for_high().await;
+    }
+
+    /// Runs the datasheet self-test procedure on both sensors with positive and negative
+    /// self-test excitation. The chip is reset afterwards and the driver configuration,
+    /// FIFO and interrupt settings are restored, also when the test fails partway.
+    pub async fn self_test(&mut self) -> Result<SelfTestReport, LSM6DSMError<B::Error>> {
+        // restore even if the test failed partway, the chip may still be excited
+        let report = self.measure_self_test().await;
+        let restored = self.restore_after_self_test().await;
+        let report = report?;
+        restored?;
+        if report.passed() {
+            Ok(report)
+        } else {
+            Err(LSM6DSMError::SelfTestFailed(report))
+        }
+    }
+
+    async fn measure_self_test(&mut self) -> Result<SelfTestReport, LSM6DSMError<B::Error>> {
+        // accelerometer: 52Hz, ±4g, everything else off
+        self.write_self_test_defaults(0x38, 0x00).await?;
+        Timer::after_millis(100).await;
+        let acc_delta_positive = self.self_test_delta(false, 0b01).await?;
+        let acc_delta_negative = self.self_test_delta(false, 0b10).await?;
+        // 0.122 mg/LSB at ±4g
+        let acc = SensorSelfTest::new(
+            acc_delta_positive.map(|delta| delta * 0.122),
+            acc_delta_negative.map(|delta| delta * 0.122),
+            ACC_SELF_TEST_RANGE_MG,
+        );
+
+        // gyroscope: 208Hz, ±2000dps, accelerometer off
+        self.write_self_test_defaults(0x00, 0x5C).await?;
+        Timer::after_millis(150).await;
+        let gyro_delta_positive = self.self_test_delta(true, 0b01).await?;
+        let gyro_delta_negative = self.self_test_delta(true, 0b11).await?;
+        // 70 mdps/LSB at ±2000dps
+        let gyro = SensorSelfTest::new(
+            gyro_delta_positive.map(|delta| delta * 0.070),
+            gyro_delta_negative.map(|delta| delta * 0.070),
+            GYRO_SELF_TEST_RANGE_DPS,
+        );
+
+        Ok(SelfTestReport { acc, gyro })
+    }
+
+    async fn restore_after_self_test(&mut self) -> Result<(), LSM6DSMError<B::Error>> {
+        self.reset().await?;
+        if self.fifo_config.mode != FifoMode::Bypass {
+            self.configure_fifo(self.fifo_config).await?;
+        }
+        self.configure_interrupts(self.interrupt_config).await
+    }
+
+    async fn write_self_test_defaults(
+        &mut self,
+        ctrl1_xl: u8,
+        ctrl2_g: u8,
+    ) -> Result<(), LSM6DSMError<B::Error>> {
+        self.write_register(CTRL1_XL, ctrl1_xl).await?;
+        self.write_register(CTRL2_G, ctrl2_g).await?;
+        // block data update and auto increment
+        self.write_register(CTRL3_C, 0x44).await?;
+        for register in [
+            CTRL4_C, CTRL5_C, CTRL6_C, CTRL7_G, CTRL8_XL, CTRL9_XL, CTRL10_C,
+        ] {
+            self.write_register(register, 0x00).await?;
+        }
+        Ok(())
+    }
+
+    // raw output change per axis when the self-test excitation `sign` (ST_G or ST_XL
+    // bits of CTRL5_C) is applied, the sensor must already be running and settled
+    async fn self_test_delta(
+        &mut self,
+        gyro: bool,
+        sign: u8,
+    ) -> Result<[f32; 3], LSM6DSMError<B::Error>> {
+        let self_test_shift = if gyro { 2 } else { 0 };
+
+        self.write_register(CTRL5_C, 0x00).await?;
+        Timer::after_millis(100).await;
+        let without_self_test = self.average_raw_samples(gyro).await?;
+
+        self.write_register(CTRL5_C, sign << self_test_shift)
+            .await?;
+        Timer::after_millis(100).await;
+        let with_self_test = self.average_raw_samples(gyro).await?;
+
+        self.write_register(CTRL5_C, 0x00).await?;
+        Ok([0, 1, 2].map(|axis| (with_self_test[axis] - without_self_test[axis]).abs()))
+    }
+
+    // discards the first sample then averages the next 5
+    async fn average_raw_samples(
+        &mut self,
+        gyro: bool,
+    ) -> Result<[f32; 3], LSM6DSMError<B::Error>> {
+        let (data_ready_mask, output_register) = if gyro {
+            (0b010, OUTX_L_G)
+        } else {
+            (0b001, OUTX_L_XL)
+        };
+
+        let mut sum = [0f32; 3];
+        for i in 0..6 {
+            let deadline = Instant::now() + Duration::from_millis(100);
+            while self.read_register(STATUS_REG).await? & data_ready_mask == 0 {
+                if Instant::now() > deadline {
+                    return Err(LSM6DSMError::DataNotReady);
+                }
+                Timer::after_millis(1).await;
+            }
+
+            let mut buffer = [0u8; 7];
+            self.spi
+                .transfer(&mut buffer, &[output_register | 0x80, 0, 0, 0, 0, 0, 0])
+                .await
+                .map_err(LSM6DSMError::Bus)?;
+            if i == 0 {
+                continue;
+            }
+            for (sum, bytes) in sum.iter_mut().zip(buffer[1..].chunks_exact(2)) {
+                *sum += i16::from_le_bytes([bytes[0], bytes[1]]) as f32;
+            }
+        }
+        Ok(sum.map(|sum| sum / 5.0))
+    }
+}
+
+/// The order in which data sets are stored in the FIFO. The pattern is made of steps at
+/// the FIFO ODR, each step holds the gyro, acc and/or timestamp data sets (3 words each,
+/// in that order) depending on the decimation of each. Steps without any data are skipped.
+struct FifoPattern {
+    // gyro, acc, timestamp, 0 if not in the FIFO
+    decimations: [u16; 3],
+    steps: u16,
+}
+
+#[derive(Clone, Copy)]
+struct FifoPosition {
+    step: u16,
+    // word within the step
+    word: usize,
+}
+
+impl FifoPattern {
+    fn new(fifo_config: &FifoConfig) -> Self {
+        let decimations = [
+            fifo_config.gyro_decimation.factor(),
+            fifo_config.acc_decimation.factor(),
+            fifo_config.timestamp_decimation.factor(),
+        ];
+        let steps = decimations
+            .iter()
+            .filter(|decimation| **decimation != 0)
+            .fold(0, |steps, decimation| match steps {
+                0 => *decimation,
+                steps => steps / gcd(steps, *decimation) * decimation,
+            });
+        Self { decimations, steps }
+    }
+
+    fn step_sets(&self, step: u16) -> [bool; 3] {
+        self.decimations
+            .map(|decimation| decimation != 0 && step.is_multiple_of(decimation))
+    }
+
+    fn step_words(&self, step: u16) -> usize {
+        self.step_sets(step).iter().filter(|set| **set).count() * 3
+    }
+
+    /// Number of FIFO ODR periods from `step` to the one after it
+    fn step_gap(&self, step: u16) -> u16 {
+        let next_step = self.next_step(step);
+        if next_step > step {
+            next_step - step
+        } else {
+            next_step + self.steps - step
+        }
+    }
+
+    fn next_step(&self, step: u16) -> u16 {
+        let mut step = step;
+        loop {
+            step = (step + 1) % self.steps;
+            if self.step_words(step) != 0 {
+                return step;
+            }
+        }
+    }
+
+    fn len_words(&self) -> usize {
+        (0..self.steps).map(|step| self.step_words(step)).sum()
+    }
+
+    /// Converts the FIFO_PATTERN word index to a step and word within that step
+    fn locate(&self, pattern: u16) -> FifoPosition {
+        let mut remaining = pattern as usize % self.len_words();
+        for step in 0..self.steps {
+            let step_words = self.step_words(step);
+            if remaining < step_words {
+                return FifoPosition {
+                    step,
+                    word: remaining,
+                };
+            }
+            remaining -= step_words;
+        }
+        FifoPosition { step: 0, word: 0 }
+    }
+}
+
+fn gcd(a: u16, b: u16) -> u16 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+// datasheet self-test output change limits
+const ACC_SELF_TEST_RANGE_MG: (f32, f32) = (90.0, 1700.0);
+const GYRO_SELF_TEST_RANGE_DPS: (f32, f32) = (150.0, 700.0);
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq)]
+pub struct AxisSelfTest {
+    /// Output change with positive / negative self-test excitation, mg or dps
+    pub positive_delta: f32,
+    pub negative_delta: f32,
+    pub passed: bool,
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq)]
+pub struct SensorSelfTest {
+    pub axes: [AxisSelfTest; 3],
+}
+
+impl SensorSelfTest {
+    fn new(positive_delta: [f32; 3], negative_delta: [f32; 3], (min, max): (f32, f32)) -> Self {
+        let in_range = |delta: f32| delta >= min && delta <= max;
+        Self {
+            axes: [0, 1, 2].map(|axis| AxisSelfTest {
+                positive_delta: positive_delta[axis],
+                negative_delta: negative_delta[axis],
+                passed: in_range(positive_delta[axis]) && in_range(negative_delta[axis]),
+            }),
+        }
+    }
+
+    pub fn passed(&self) -> bool {
+        self.axes.iter().all(|axis| axis.passed)
+    }
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq)]
+pub struct SelfTestReport {
+    pub acc: SensorSelfTest,
+    pub gyro: SensorSelfTest,
+}
 
-        let buffer = &buffer[1..];
-        let gyro_x = i16::from_le_bytes([buffer[0], buffer[1]]);
-        let gyro_y = i16::from_le_bytes([buffer[2], buffer[3]]);
-        let gyro_z = i16::from_le_bytes([buffer[4], buffer[5]]);
-
-        let acc_x = i16::from_le_bytes([buffer[6], buffer[7]]);
-        let acc_y = i16::from_le_bytes([buffer[8], buffer[9]]);
-        let acc_z = i16::from_le_bytes([buffer[10], buffer[11]]);
-
-        let acc_scale = 16.0 / 32768.0 * 9.81; // ±16g range
-        let gyro_scale = 2000.0 / 32768.0; // ±2000dps range
-
-        Ok(IMUData {
-            acc: [
-                acc_x as f32 * acc_scale,
-                acc_y as f32 * acc_scale,
-                acc_z as f32 * acc_scale,
-            ],
-            gyro: [
-                gyro_x as f32 * gyro_scale,
-                gyro_y as f32 * gyro_scale,
-                gyro_z as f32 * gyro_scale,
-            ],
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.acc.passed() && self.gyro.passed()
+    }
+}
+
+// 256 LSB/°C, 0 is 25°C
+fn convert_temperature(raw: i16) -> f32 {
+    25.0 + raw as f32 / 256.0
+}
+
+/// Gyro zero-rate offset modelled per axis as a polynomial of the temperature:
+/// `bias = c0 + c1 * dt + c2 * dt^2` in deg/s, where `dt = temperature - reference_temperature`
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq)]
+pub struct GyroTemperatureCompensation {
+    pub reference_temperature: f32,
+    /// `[c0, c1, c2]` for x, y and z
+    pub coefficients: [[f32; 3]; 3],
+}
+
+impl GyroTemperatureCompensation {
+    pub fn bias(&self, temperature: f32) -> [f32; 3] {
+        let dt = temperature - self.reference_temperature;
+        self.coefficients
+            .map(|[c0, c1, c2]| c0 + c1 * dt + c2 * dt * dt)
+    }
+
+    pub fn apply(&self, data: &mut IMUData) {
+        let bias = self.bias(data.temperature);
+        for (gyro, bias) in data.gyro.iter_mut().zip(bias) {
+            *gyro -= bias;
+        }
+    }
+}
+
+/// Least squares fit of a `GyroTemperatureCompensation` from uncompensated gyro samples taken
+/// while the IMU is stationary and warming up or cooling down. Samples are accumulated
+/// into sums so the calibration run doesn't need to store them.
+#[derive(Debug, Clone)]
+pub struct GyroTemperatureFit {
+    reference_temperature: f32,
+    // sums of dt^0..dt^4
+    dt_powers: [f64; 5],
+    // per axis sums of gyro * dt^0..dt^2
+    gyro_dt_powers: [[f64; 3]; 3],
+}
+
+impl GyroTemperatureFit {
+    pub fn new(reference_temperature: f32) -> Self {
+        Self {
+            reference_temperature,
+            dt_powers: [0.0; 5],
+            gyro_dt_powers: [[0.0; 3]; 3],
+        }
+    }
+
+    pub fn add(&mut self, data: &IMUData) {
+        let dt = (data.temperature - self.reference_temperature) as f64;
+        let mut power = 1.0;
+        for sum in self.dt_powers.iter_mut() {
+            *sum += power;
+            power *= dt;
+        }
+        for (axis, sums) in self.gyro_dt_powers.iter_mut().enumerate() {
+            let mut power = 1.0;
+            for sum in sums.iter_mut() {
+                *sum += data.gyro[axis] as f64 * power;
+                power *= dt;
+            }
+        }
+    }
+
+    /// Returns `None` if the samples don't span enough temperatures to fit a quadratic
+    pub fn solve(&self) -> Option<GyroTemperatureCompensation> {
+        let s = &self.dt_powers;
+        let normal = Matrix3::new(
+            s[0], s[1], s[2], //
+            s[1], s[2], s[3], //
+            s[2], s[3], s[4],
+        );
+        let inverse = normal.try_inverse()?;
+
+        let mut coefficients = [[0.0; 3]; 3];
+        for (coefficients, dt_powers) in coefficients.iter_mut().zip(self.gyro_dt_powers) {
+            let solution = inverse * Vector3::from(dt_powers);
+            if solution.iter().any(|c| !c.is_finite()) {
+                return None;
+            }
+            *coefficients = [solution[0] as f32, solution[1] as f32, solution[2] as f32];
+        }
+        Some(GyroTemperatureCompensation {
+            reference_temperature: self.reference_temperature,
+            coefficients,
         })
     }
 }
 
-#[derive(defmt::Format, Debug, Clone)]
+/// Maps the chip's 24 bit timestamp counter to `Instant`, estimating the actual counter
+/// period from consecutive syncs since the chip oscillator is not trimmed.
+#[derive(defmt::Format, Debug, Clone, Copy)]
+pub struct TimestampMapper {
+    anchor_raw: u32,
+    anchor_instant: Option<Instant>,
+    tick_us: f32,
+}
+
+impl TimestampMapper {
+    pub fn new() -> Self {
+        Self {
+            anchor_raw: 0,
+            anchor_instant: None,
+            tick_us: TIMESTAMP_TICK_US,
+        }
+    }
+
+    /// Counter period in microseconds
+    pub fn tick_us(&self) -> f32 {
+        self.tick_us
+    }
+
+    pub fn is_stale(&self) -> bool {
+        match self.anchor_instant {
+            Some(anchor_instant) => Instant::now() - anchor_instant > TIMESTAMP_SYNC_INTERVAL,
+            None => true,
+        }
+    }
+
+    /// `raw` is the counter value read at `instant`
+    pub fn sync(&mut self, raw: u32, instant: Instant) {
+        if let Some(anchor_instant) = self.anchor_instant {
+            let elapsed_us = instant
+                .as_micros()
+                .saturating_sub(anchor_instant.as_micros());
+            let elapsed_ticks = Self::ticks_between(self.anchor_raw, raw);
+            // short intervals are dominated by the SPI transfer jitter
+            if elapsed_us > 500_000 && elapsed_ticks > 0 {
+                let measured = elapsed_us as f32 / elapsed_ticks as f32;
+                // reject readings more than 10% off nominal, such as after a counter reset
+                if (measured - TIMESTAMP_TICK_US).abs() < TIMESTAMP_TICK_US * 0.1 {
+                    self.tick_us = self.tick_us * 0.8 + measured * 0.2;
+                }
+            }
+        }
+        self.anchor_raw = raw;
+        self.anchor_instant = Some(instant);
+    }
+
+    /// Valid for counter values within ~3 minutes of the last sync
+    pub fn to_instant(&self, raw: u32) -> Instant {
+        let Some(anchor_instant) = self.anchor_instant else {
+            return Instant::now();
+        };
+        let offset_us = Self::ticks_between(self.anchor_raw, raw) as f32 * self.tick_us;
+        let micros = anchor_instant.as_micros() as i64 + offset_us as i64;
+        Instant::from_micros(micros.max(0) as u64)
+    }
+
+    // signed difference of two 24 bit counter values
+    fn ticks_between(from: u32, to: u32) -> i32 {
+        (to.wrapping_sub(from) << 8) as i32 >> 8
+    }
+}
+
+impl Default for TimestampMapper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy)]
 pub struct IMUData {
-    pub acc: [f32; 3],  // m/s^2
-    pub gyro: [f32; 3], // deg/s
+    pub acc: [f32; 3],    // m/s^2
+    pub gyro: [f32; 3],   // deg/s
+    pub temperature: f32, // °C
+    pub timestamp: Instant,
+}
+
+impl Default for IMUData {
+    fn default() -> Self {
+        Self {
+            acc: [0.0; 3],
+            gyro: [0.0; 3],
+            temperature: 25.0,
+            timestamp: Instant::from_ticks(0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock::{SimulatedBusError, SimulatedLSM6DSM};
+    use embassy_futures::block_on;
+
+    fn reset_imu(sim: SimulatedLSM6DSM) -> LSM6DSM<SimulatedLSM6DSM> {
+        let mut imu = LSM6DSM::new(sim);
+        block_on(imu.reset()).unwrap();
+        imu
+    }
+
+    #[test]
+    fn reset_faults() {
+        let mut imu = LSM6DSM::new(SimulatedLSM6DSM::new());
+        imu.spi.faults.who_am_i = Some(0x69);
+        assert!(matches!(
+            block_on(imu.reset()),
+            Err(LSM6DSMError::WrongChipId(0x69))
+        ));
+
+        imu.spi.faults.who_am_i = None;
+        imu.spi.faults.stuck_in_reset = true;
+        assert!(matches!(
+            block_on(imu.reset()),
+            Err(LSM6DSMError::BootTimeout)
+        ));
+
+        imu.spi.faults.bus_error = true;
+        assert!(matches!(
+            block_on(imu.reset()),
+            Err(LSM6DSMError::Bus(SimulatedBusError))
+        ));
+    }
+
+    #[test]
+    fn read_converts_to_si_units() {
+        let mut sim = SimulatedLSM6DSM::new();
+        sim.acc = [1.0, -2.0, 9.81];
+        sim.gyro = [100.0, -5.0, 0.5];
+        sim.temperature = 40.0;
+        let mut imu = LSM6DSM::new_with_config(
+            sim,
+            LSM6DSMConfig {
+                acc_full_scale: AccFullScale::G4,
+                gyro_full_scale: GyroFullScale::Dps500,
+                ..Default::default()
+            },
+        );
+        block_on(imu.reset()).unwrap();
+
+        let data = block_on(imu.read()).unwrap();
+        for axis in 0..3 {
+            assert!((data.acc[axis] - imu.spi.acc[axis]).abs() < 0.002);
+            assert!((data.gyro[axis] - imu.spi.gyro[axis]).abs() < 0.02);
+        }
+        assert!((data.temperature - 40.0).abs() < 0.01);
+
+        imu.spi.faults.no_data = true;
+        assert!(matches!(
+            block_on(imu.read()),
+            Err(LSM6DSMError::DataNotReady)
+        ));
+    }
+
+    #[test]
+    fn fifo_repeats_decimated_samples() {
+        let mut imu = reset_imu(SimulatedLSM6DSM::new());
+        block_on(imu.configure_fifo(FifoConfig {
+            acc_decimation: FifoDecimation::Div2,
+            ..Default::default()
+        }))
+        .unwrap();
+        imu.spi.gyro = [10.0, 0.0, 0.0];
+        imu.spi.advance_fifo(4);
+        imu.spi.gyro = [20.0, 0.0, 0.0];
+        imu.spi.advance_fifo(4);
+
+        let mut samples = [IMUData::default(); 16];
+        assert_eq!(block_on(imu.read_fifo(&mut samples)).unwrap(), 8);
+        assert_eq!(imu.spi.fifo_len(), 0);
+        for (i, sample) in samples[..8].iter().enumerate() {
+            let expected_gyro = if i < 4 { 10.0 } else { 20.0 };
+            assert!((sample.gyro[0] - expected_gyro).abs() < 0.1);
+            assert!((sample.acc[2] - STANDARD_GRAVITY).abs() < 0.01);
+            if i > 0 {
+                assert!(sample.timestamp >= samples[i - 1].timestamp);
+            }
+        }
+    }
+
+    #[test]
+    fn fifo_read_skips_partial_step() {
+        let mut imu = reset_imu(SimulatedLSM6DSM::new());
+        block_on(imu.configure_fifo(FifoConfig::default())).unwrap();
+        imu.spi.advance_fifo(3);
+        // drop the first word, as if a previous read was interrupted
+        let mut word = [0u8; 3];
+        block_on(imu.read_fifo_words(&mut word)).unwrap();
+
+        let mut samples = [IMUData::default(); 4];
+        assert_eq!(block_on(imu.read_fifo(&mut samples)).unwrap(), 2);
+    }
+
+    #[test]
+    fn self_test() {
+        let mut imu = reset_imu(SimulatedLSM6DSM::new());
+        assert!(block_on(imu.self_test()).is_ok());
+
+        imu.spi.gyro_self_test_response = 50.0;
+        let Err(LSM6DSMError::SelfTestFailed(report)) = block_on(imu.self_test()) else {
+            panic!("self-test passed with a weak gyro");
+        };
+        assert!(report.acc.passed());
+        assert!(!report.gyro.passed());
+
+        // the configuration is restored when the test can't finish
+        let configured = [CTRL1_XL, CTRL2_G, CTRL3_C].map(|register| imu.spi.register(register));
+        imu.spi.faults.no_data = true;
+        assert!(matches!(
+            block_on(imu.self_test()),
+            Err(LSM6DSMError::DataNotReady)
+        ));
+        assert_eq!(
+            [CTRL1_XL, CTRL2_G, CTRL3_C].map(|register| imu.spi.register(register)),
+            configured
+        );
+        assert_eq!(imu.spi.register(CTRL5_C), 0);
+    }
 }
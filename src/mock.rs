@@ -0,0 +1,7 @@
+// Simulated peripherals for running the library against fake hardware
+
+mod lsm6dsm;
+mod ublox;
+
+pub use lsm6dsm::{SimulatedBusError, SimulatedFaults, SimulatedLSM6DSM};
+pub use ublox::SimulatedUblox;
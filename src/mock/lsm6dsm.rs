@@ -0,0 +1,526 @@
+use crate::lsm6dsm::STANDARD_GRAVITY;
+use embassy_time::{Duration, Instant};
+use embedded_hal_async::spi::{Error, ErrorKind, ErrorType, Operation, SpiDevice};
+use heapless::Deque;
+
+// an independent copy of the register map, so a wrong address in the driver shows up in tests
+const FIFO_CTRL1: u8 = 0x06;
+const FIFO_CTRL2: u8 = 0x07;
+const FIFO_CTRL3: u8 = 0x08;
+const FIFO_CTRL4: u8 = 0x09;
+const FIFO_CTRL5: u8 = 0x0A;
+const INT1_CTRL: u8 = 0x0D;
+const INT2_CTRL: u8 = 0x0E;
+const WHO_AM_I: u8 = 0x0F;
+const CTRL1_XL: u8 = 0x10;
+const CTRL2_G: u8 = 0x11;
+const CTRL3_C: u8 = 0x12;
+const CTRL5_C: u8 = 0x14;
+const CTRL10_C: u8 = 0x19;
+const STATUS_REG: u8 = 0x1E;
+const OUT_TEMP_L: u8 = 0x20;
+const OUTX_L_G: u8 = 0x22;
+const OUTX_L_XL: u8 = 0x28;
+const FIFO_STATUS1: u8 = 0x3A;
+const FIFO_STATUS2: u8 = 0x3B;
+const FIFO_STATUS3: u8 = 0x3C;
+const FIFO_STATUS4: u8 = 0x3D;
+const FIFO_DATA_OUT_L: u8 = 0x3E;
+const FIFO_DATA_OUT_H: u8 = 0x3F;
+const TIMESTAMP0_REG: u8 = 0x40;
+const TIMESTAMP2_REG: u8 = 0x42;
+const WAKE_UP_DUR: u8 = 0x5C;
+
+const LSM6DSM_ID: u8 = 0x6A;
+// 4 kbyte
+const FIFO_CAPACITY_WORDS: usize = 2048;
+// time until BOOT and SW_RESET clear
+const BOOT_TIME_US: u64 = 15_000;
+const SW_RESET_TIME_US: u64 = 50;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SimulatedBusError;
+
+impl Error for SimulatedBusError {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Other
+    }
+}
+
+/// Faults the simulated chip can be told to exhibit
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SimulatedFaults {
+    /// Every transaction fails
+    pub bus_error: bool,
+    /// Reported from WHO_AM_I instead of the real ID
+    pub who_am_i: Option<u8>,
+    /// BOOT and SW_RESET never clear
+    pub stuck_in_reset: bool,
+    /// Every gyro and accelerometer output word reads as this value
+    pub stuck_output: Option<i16>,
+    /// The data ready flags never get set
+    pub no_data: bool,
+}
+
+/// Register level model of the LSM6DSM behind an SPI bus: auto-increment, reset and boot,
+/// output data from the injected physical values in the configured full scale, self-test
+/// excitation, the timestamp counter and the FIFO.
+pub struct SimulatedLSM6DSM {
+    registers: [u8; 128],
+    /// m/s^2
+    pub acc: [f32; 3],
+    /// deg/s
+    pub gyro: [f32; 3],
+    /// °C
+    pub temperature: f32,
+    /// Output change while the accelerometer self-test is enabled, mg
+    pub acc_self_test_response: f32,
+    /// Output change while the gyro self-test is enabled, dps
+    pub gyro_self_test_response: f32,
+    pub faults: SimulatedFaults,
+    reset_until: Option<Instant>,
+    timestamp_origin: Instant,
+    fifo: Deque<u16, FIFO_CAPACITY_WORDS>,
+    fifo_step: u16,
+    fifo_pattern: u16,
+    fifo_overrun: bool,
+    fifo_word: u16,
+    // transaction state
+    address: u8,
+    reading: bool,
+    first_byte: bool,
+}
+
+impl SimulatedLSM6DSM {
+    /// A chip at rest, flat on the table, with self-test responses within the datasheet limits
+    pub fn new() -> Self {
+        let mut sim = Self {
+            registers: [0; 128],
+            acc: [0.0, 0.0, STANDARD_GRAVITY],
+            gyro: [0.0; 3],
+            temperature: 25.0,
+            acc_self_test_response: 500.0,
+            gyro_self_test_response: 300.0,
+            faults: SimulatedFaults::default(),
+            reset_until: None,
+            timestamp_origin: Instant::now(),
+            fifo: Deque::new(),
+            fifo_step: 0,
+            fifo_pattern: 0,
+            fifo_overrun: false,
+            fifo_word: 0,
+            address: 0,
+            reading: false,
+            first_byte: true,
+        };
+        sim.power_on_reset();
+        sim
+    }
+
+    fn power_on_reset(&mut self) {
+        self.registers = [0; 128];
+        self.registers[WHO_AM_I as usize] = LSM6DSM_ID;
+        // IF_INC
+        self.registers[CTRL3_C as usize] = 0b0000_0100;
+        self.timestamp_origin = Instant::now();
+        self.clear_fifo();
+    }
+
+    /// Raw register content, without read side effects
+    pub fn register(&self, address: u8) -> u8 {
+        self.registers[address as usize & 0x7F]
+    }
+
+    pub fn set_register(&mut self, address: u8, value: u8) {
+        self.registers[address as usize & 0x7F] = value;
+    }
+
+    /// Level of the INT1 pin from the events routed to it in INT1_CTRL
+    pub fn int1(&self) -> bool {
+        self.interrupt_active(self.register(INT1_CTRL))
+    }
+
+    /// Level of the INT2 pin from the events routed to it in INT2_CTRL
+    pub fn int2(&self) -> bool {
+        self.interrupt_active(self.register(INT2_CTRL))
+    }
+
+    fn interrupt_active(&self, ctrl: u8) -> bool {
+        let [_, status2] = self.fifo_status();
+        let data_ready = self.status();
+        (ctrl & 0b0000_0001 != 0 && data_ready & 0b001 != 0)
+            || (ctrl & 0b0000_0010 != 0 && data_ready & 0b010 != 0)
+            || (ctrl & 0b0000_1000 != 0 && status2 & 0b1000_0000 != 0)
+            || (ctrl & 0b0001_0000 != 0 && status2 & 0b0100_0000 != 0)
+            || (ctrl & 0b0010_0000 != 0 && status2 & 0b0010_0000 != 0)
+    }
+
+    /// Number of unread FIFO words
+    pub fn fifo_len(&self) -> usize {
+        self.fifo.len()
+    }
+
+    /// Runs the FIFO for `steps` periods of its ODR, storing the gyro, accelerometer and
+    /// timestamp data sets their decimation asks for. Does nothing in bypass mode.
+    pub fn advance_fifo(&mut self, steps: u32) {
+        let mode = self.register(FIFO_CTRL5) & 0b111;
+        let odr = self.register(FIFO_CTRL5) >> 3 & 0b1111;
+        if mode == 0 || odr == 0 {
+            return;
+        }
+
+        let decimations = self.fifo_decimations();
+        let pattern_steps = pattern_steps(&decimations);
+        if pattern_steps == 0 {
+            return;
+        }
+        for _ in 0..steps {
+            let step = self.fifo_step;
+            self.fifo_step = (self.fifo_step + 1) % pattern_steps;
+            for (data_set, decimation) in decimations.iter().enumerate() {
+                if *decimation == 0 || !step.is_multiple_of(*decimation) {
+                    continue;
+                }
+                let words = match data_set {
+                    0 => self.gyro_output(),
+                    1 => self.acc_output(),
+                    _ => self.fifo_timestamp_words(),
+                };
+                for word in words {
+                    self.push_fifo_word(word as u16);
+                }
+            }
+        }
+    }
+
+    fn push_fifo_word(&mut self, word: u16) {
+        if self.fifo.is_full() {
+            self.fifo_overrun = true;
+            // FIFO mode stops, the continuous modes overwrite the oldest data
+            if self.register(FIFO_CTRL5) & 0b111 == 0b001 {
+                return;
+            }
+            self.pop_fifo_word();
+        }
+        self.fifo.push_back(word).ok();
+    }
+
+    fn pop_fifo_word(&mut self) -> u16 {
+        let Some(word) = self.fifo.pop_front() else {
+            return 0;
+        };
+        let pattern_words = pattern_words(&self.fifo_decimations());
+        if pattern_words != 0 {
+            self.fifo_pattern = (self.fifo_pattern + 1) % pattern_words;
+        }
+        word
+    }
+
+    fn clear_fifo(&mut self) {
+        self.fifo.clear();
+        self.fifo_step = 0;
+        self.fifo_pattern = 0;
+        self.fifo_overrun = false;
+    }
+
+    // gyro, acc, timestamp
+    fn fifo_decimations(&self) -> [u16; 3] {
+        let timestamp_in_fifo = self.register(FIFO_CTRL2) & 0b1000_0000 != 0;
+        [
+            decimation_factor(self.register(FIFO_CTRL3) >> 3),
+            decimation_factor(self.register(FIFO_CTRL3)),
+            if timestamp_in_fifo {
+                decimation_factor(self.register(FIFO_CTRL4) >> 3)
+            } else {
+                0
+            },
+        ]
+    }
+
+    // TIMESTAMP[15:8], TIMESTAMP[23:16], unused, TIMESTAMP[7:0], step counter
+    fn fifo_timestamp_words(&self) -> [i16; 3] {
+        let [low, mid, high, _] = self.timestamp().to_le_bytes();
+        [
+            i16::from_le_bytes([mid, high]),
+            i16::from_le_bytes([0, low]),
+            0,
+        ]
+    }
+
+    fn fifo_status(&self) -> [u8; 2] {
+        let unread_words = self.fifo.len() as u16;
+        let watermark =
+            u16::from_le_bytes([self.register(FIFO_CTRL1), self.register(FIFO_CTRL2) & 0b111]);
+        let [low, high] = unread_words.min(0x7FF).to_le_bytes();
+        let status2 = high
+            | ((watermark != 0 && unread_words >= watermark) as u8) << 7
+            | (self.fifo_overrun as u8) << 6
+            | (self.fifo.is_full() as u8) << 5
+            | (self.fifo.is_empty() as u8) << 4;
+        [low, status2]
+    }
+
+    fn timestamp(&self) -> u32 {
+        if self.register(CTRL10_C) & 0b0010_0000 == 0 {
+            return 0;
+        }
+        let tick_us = if self.register(WAKE_UP_DUR) & 0b0001_0000 != 0 {
+            25
+        } else {
+            6400
+        };
+        ((Instant::now() - self.timestamp_origin).as_micros() / tick_us) as u32 & 0xFF_FFFF
+    }
+
+    fn in_reset(&mut self) -> bool {
+        match self.reset_until {
+            Some(_) if self.faults.stuck_in_reset => true,
+            Some(until) if Instant::now() < until => true,
+            Some(_) => {
+                self.reset_until = None;
+                self.registers[CTRL3_C as usize] &= !0b1000_0001;
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn status(&self) -> u8 {
+        if self.faults.no_data {
+            return 0;
+        }
+        let acc_on = self.register(CTRL1_XL) >> 4 != 0;
+        let gyro_on = self.register(CTRL2_G) >> 4 != 0;
+        (acc_on as u8) | (gyro_on as u8) << 1 | ((acc_on || gyro_on) as u8) << 2
+    }
+
+    fn acc_output(&self) -> [i16; 3] {
+        if let Some(stuck) = self.faults.stuck_output {
+            return [stuck; 3];
+        }
+        let mg_per_lsb = match self.register(CTRL1_XL) >> 2 & 0b11 {
+            0b00 => 0.061,
+            0b10 => 0.122,
+            0b11 => 0.244,
+            _ => 0.488,
+        };
+        let self_test = match self.register(CTRL5_C) & 0b11 {
+            0b01 => self.acc_self_test_response,
+            0b10 => -self.acc_self_test_response,
+            _ => 0.0,
+        };
+        self.acc
+            .map(|acc| to_raw((acc / STANDARD_GRAVITY * 1000.0 + self_test) / mg_per_lsb))
+    }
+
+    fn gyro_output(&self) -> [i16; 3] {
+        if let Some(stuck) = self.faults.stuck_output {
+            return [stuck; 3];
+        }
+        let ctrl2_g = self.register(CTRL2_G);
+        let mdps_per_lsb = if ctrl2_g & 0b10 != 0 {
+            4.375
+        } else {
+            match ctrl2_g >> 2 & 0b11 {
+                0b00 => 8.75,
+                0b01 => 17.5,
+                0b10 => 35.0,
+                _ => 70.0,
+            }
+        };
+        let self_test = match self.register(CTRL5_C) >> 2 & 0b11 {
+            0b01 => self.gyro_self_test_response,
+            0b11 => -self.gyro_self_test_response,
+            _ => 0.0,
+        };
+        self.gyro
+            .map(|gyro| to_raw((gyro + self_test) * 1000.0 / mdps_per_lsb))
+    }
+
+    fn read(&mut self, address: u8) -> u8 {
+        match address {
+            WHO_AM_I => self.faults.who_am_i.unwrap_or(LSM6DSM_ID),
+            CTRL3_C => {
+                self.in_reset();
+                self.register(CTRL3_C)
+            }
+            STATUS_REG => self.status(),
+            OUT_TEMP_L..=0x21 => {
+                let raw = to_raw((self.temperature - 25.0) * 256.0);
+                raw.to_le_bytes()[(address - OUT_TEMP_L) as usize]
+            }
+            OUTX_L_G..=0x27 => {
+                let offset = (address - OUTX_L_G) as usize;
+                self.gyro_output()[offset / 2].to_le_bytes()[offset % 2]
+            }
+            OUTX_L_XL..=0x2D => {
+                let offset = (address - OUTX_L_XL) as usize;
+                self.acc_output()[offset / 2].to_le_bytes()[offset % 2]
+            }
+            FIFO_STATUS1 => self.fifo_status()[0],
+            FIFO_STATUS2 => self.fifo_status()[1],
+            FIFO_STATUS3 => self.fifo_pattern.to_le_bytes()[0],
+            FIFO_STATUS4 => self.fifo_pattern.to_le_bytes()[1] & 0b11,
+            FIFO_DATA_OUT_L => {
+                self.fifo_word = self.pop_fifo_word();
+                self.fifo_overrun = false;
+                self.fifo_word.to_le_bytes()[0]
+            }
+            FIFO_DATA_OUT_H => self.fifo_word.to_le_bytes()[1],
+            TIMESTAMP0_REG..=TIMESTAMP2_REG => {
+                self.timestamp().to_le_bytes()[(address - TIMESTAMP0_REG) as usize]
+            }
+            _ => self.register(address),
+        }
+    }
+
+    fn write(&mut self, address: u8, value: u8) {
+        match address {
+            // read only
+            WHO_AM_I | STATUS_REG..=FIFO_DATA_OUT_H => {}
+            CTRL3_C if value & 0b1000_0001 != 0 => {
+                self.power_on_reset();
+                self.registers[CTRL3_C as usize] |= value & 0b1000_0001;
+                let reset_time = if value & 0b1000_0000 != 0 {
+                    BOOT_TIME_US
+                } else {
+                    SW_RESET_TIME_US
+                };
+                self.reset_until = Some(Instant::now() + Duration::from_micros(reset_time));
+            }
+            FIFO_CTRL5 => {
+                // bypass mode clears the FIFO
+                if value & 0b111 == 0 {
+                    self.clear_fifo();
+                }
+                self.set_register(address, value);
+            }
+            TIMESTAMP2_REG => {
+                if value == 0xAA {
+                    self.timestamp_origin = Instant::now();
+                }
+            }
+            _ => self.set_register(address, value),
+        }
+    }
+
+    fn exchange(&mut self, mosi: u8) -> u8 {
+        if self.first_byte {
+            self.first_byte = false;
+            self.address = mosi & 0x7F;
+            self.reading = mosi & 0x80 != 0;
+            return 0;
+        }
+
+        let address = self.address;
+        // the FIFO output wraps so it can be drained in one burst
+        self.address = match address {
+            FIFO_DATA_OUT_H => FIFO_DATA_OUT_L,
+            _ if self.register(CTRL3_C) & 0b0000_0100 != 0 => (address + 1) & 0x7F,
+            _ => address,
+        };
+        if self.reading {
+            self.read(address)
+        } else {
+            self.write(address, mosi);
+            0
+        }
+    }
+}
+
+impl Default for SimulatedLSM6DSM {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ErrorType for SimulatedLSM6DSM {
+    type Error = SimulatedBusError;
+}
+
+impl SpiDevice for SimulatedLSM6DSM {
+    async fn transaction(
+        &mut self,
+        operations: &mut [Operation<'_, u8>],
+    ) -> Result<(), Self::Error> {
+        if self.faults.bus_error {
+            return Err(SimulatedBusError);
+        }
+
+        self.first_byte = true;
+        for operation in operations {
+            match operation {
+                Operation::Read(read) => {
+                    for byte in read.iter_mut() {
+                        *byte = self.exchange(0);
+                    }
+                }
+                Operation::Write(write) => {
+                    for byte in write.iter() {
+                        self.exchange(*byte);
+                    }
+                }
+                Operation::Transfer(read, write) => {
+                    for i in 0..read.len().max(write.len()) {
+                        let miso = self.exchange(write.get(i).copied().unwrap_or(0));
+                        if let Some(byte) = read.get_mut(i) {
+                            *byte = miso;
+                        }
+                    }
+                }
+                Operation::TransferInPlace(buffer) => {
+                    for byte in buffer.iter_mut() {
+                        *byte = self.exchange(*byte);
+                    }
+                }
+                Operation::DelayNs(_) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+// DEC_FIFO field to decimation factor, 0 if not in the FIFO
+fn decimation_factor(bits: u8) -> u16 {
+    match bits & 0b111 {
+        0 => 0,
+        1 => 1,
+        2 => 2,
+        3 => 3,
+        4 => 4,
+        5 => 8,
+        6 => 16,
+        _ => 32,
+    }
+}
+
+fn pattern_steps(decimations: &[u16; 3]) -> u16 {
+    let mut steps = 0;
+    for decimation in decimations.iter().filter(|decimation| **decimation != 0) {
+        steps = match steps {
+            0 => *decimation,
+            steps => (1..)
+                .map(|n| steps * n)
+                .find(|n| n.is_multiple_of(*decimation))
+                .unwrap(),
+        };
+    }
+    steps
+}
+
+fn pattern_words(decimations: &[u16; 3]) -> u16 {
+    (0..pattern_steps(decimations))
+        .map(|step| {
+            decimations
+                .iter()
+                .filter(|decimation| **decimation != 0 && step.is_multiple_of(**decimation))
+                .count() as u16
+                * 3
+        })
+        .sum()
+}
+
+// rounds to nearest, float to int casts saturate
+fn to_raw(value: f32) -> i16 {
+    (value + 0.5f32.copysign(value)) as i16
+}
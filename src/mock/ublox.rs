@@ -0,0 +1,162 @@
+use crate::gps_config::{SetBaudRate, cfg_prt_payload};
+use crate::ubx::{
+    CLASS_ACK, CLASS_CFG, ID_ACK_ACK, ID_ACK_NAK, ID_CFG_MSG, ID_CFG_NAV5, ID_CFG_PRT, ID_CFG_RATE,
+    MAX_PAYLOAD, UbxDecoder, UbxFrame,
+};
+use core::convert::Infallible;
+use embedded_io_async::{ErrorType, Read, Write};
+use heapless::{Deque, Vec};
+
+/// u-blox receiver on the other end of a UART. It answers the CFG messages used by
+/// `gps_config` and only understands the host while both sides use the same baud rate, bytes
+/// sent at another baud rate arrive as zeros.
+pub struct SimulatedUblox {
+    /// Baud rate of the receiver
+    pub baud_rate: u32,
+    /// Baud rate of the host side of the UART
+    pub host_baud_rate: u32,
+    /// The receiver doesn't answer anything
+    pub silent: bool,
+    /// CFG message ids that are dropped without an answer
+    pub ignored: Vec<u8, 8>,
+    /// CFG message ids that are answered with a NAK
+    pub rejected: Vec<u8, 8>,
+    pub measurement_period_ms: u16,
+    pub dynamic_model: u8,
+    /// Rates set with CFG-MSG as (class, id, rate)
+    pub message_rates: Vec<(u8, u8, u8), 16>,
+    // bytes for the host and the baud rate they were sent at
+    output: Deque<(u8, u32), 1024>,
+    decoder: UbxDecoder,
+}
+
+impl SimulatedUblox {
+    /// Receiver and host both at `baud_rate`, with the factory configuration
+    pub fn new(baud_rate: u32) -> Self {
+        Self {
+            baud_rate,
+            host_baud_rate: baud_rate,
+            silent: false,
+            ignored: Vec::new(),
+            rejected: Vec::new(),
+            measurement_period_ms: 1000,
+            dynamic_model: 0,
+            message_rates: Vec::new(),
+            output: Deque::new(),
+            decoder: UbxDecoder::new(),
+        }
+    }
+
+    /// Queues bytes for the host at the receiver's current baud rate
+    pub fn send(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.output.push_back((byte, self.baud_rate)).ok();
+        }
+    }
+
+    pub fn message_rate(&self, class: u8, id: u8) -> Option<u8> {
+        self.message_rates
+            .iter()
+            .find(|(c, i, _)| (*c, *i) == (class, id))
+            .map(|(_, _, rate)| *rate)
+    }
+
+    fn receive(&mut self, byte: u8) {
+        let Some(frame) = self.decoder.push(byte) else {
+            return;
+        };
+        if frame.class != CLASS_CFG {
+            return;
+        }
+        let id = frame.id;
+        let Ok(payload) = Vec::<u8, MAX_PAYLOAD>::from_slice(frame.payload) else {
+            return;
+        };
+
+        if self.ignored.contains(&id) {
+            return;
+        }
+        let accepted = !self.rejected.contains(&id)
+            && match (id, payload.len()) {
+                // poll, answered with the configuration of UART1
+                (ID_CFG_PRT, 0) => {
+                    let mut prt = [0; 20 + 8];
+                    let payload = cfg_prt_payload(self.baud_rate);
+                    if let Some(bytes) =
+                        UbxFrame::new(CLASS_CFG, ID_CFG_PRT, &payload).encode(&mut prt)
+                    {
+                        self.send(bytes);
+                    }
+                    true
+                }
+                (ID_CFG_PRT, 20) => {
+                    self.baud_rate = u32::from_le_bytes(payload[8..12].try_into().unwrap());
+                    true
+                }
+                (ID_CFG_RATE, 6) => {
+                    self.measurement_period_ms = u16::from_le_bytes([payload[0], payload[1]]);
+                    true
+                }
+                (ID_CFG_MSG, 3) => {
+                    let (class, id, rate) = (payload[0], payload[1], payload[2]);
+                    self.message_rates
+                        .retain(|(c, i, _)| (*c, *i) != (class, id));
+                    self.message_rates.push((class, id, rate)).is_ok()
+                }
+                (ID_CFG_NAV5, 36) => {
+                    if payload[0] & 0x01 != 0 {
+                        self.dynamic_model = payload[2];
+                    }
+                    true
+                }
+                _ => false,
+            };
+
+        let answer = if accepted { ID_ACK_ACK } else { ID_ACK_NAK };
+        let mut buffer = [0; 10];
+        if let Some(bytes) = UbxFrame::new(CLASS_ACK, answer, &[CLASS_CFG, id]).encode(&mut buffer)
+        {
+            self.send(bytes);
+        }
+    }
+}
+
+impl ErrorType for SimulatedUblox {
+    type Error = Infallible;
+}
+
+impl Read for SimulatedUblox {
+    /// Returns 0 once everything sent by the receiver has been read
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let mut length = 0;
+        while length < buf.len()
+            && let Some((byte, baud_rate)) = self.output.pop_front()
+        {
+            buf[length] = if baud_rate == self.host_baud_rate {
+                byte
+            } else {
+                0
+            };
+            length += 1;
+        }
+        Ok(length)
+    }
+}
+
+impl Write for SimulatedUblox {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        if !self.silent && self.host_baud_rate == self.baud_rate {
+            for &byte in buf {
+                self.receive(byte);
+            }
+        }
+        Ok(buf.len())
+    }
+}
+
+impl SetBaudRate for SimulatedUblox {
+    fn set_baud_rate(&mut self, baud_rate: u32) -> bool {
+        self.host_baud_rate = baud_rate;
+        true
+    }
+}
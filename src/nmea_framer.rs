@@ -0,0 +1,186 @@
+use heapless::String;
+
+/// Longest sentence allowed by NMEA 0183, from the `$` to the line ending inclusive
+pub const MAX_SENTENCE_LENGTH: usize = 82;
+
+#[derive(defmt::Format, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FramerStats {
+    /// Sentences with a valid checksum
+    pub sentences: u32,
+    /// Sentences cut short by a new `$`, containing bytes that aren't printable ASCII or
+    /// without a `*hh` checksum
+    pub framing_errors: u32,
+    pub checksum_errors: u32,
+    /// Sentences longer than `MAX_SENTENCE_LENGTH`
+    pub overflow_errors: u32,
+}
+
+impl FramerStats {
+    /// Sentences dropped for any reason
+    pub fn errors(&self) -> u32 {
+        self.framing_errors + self.checksum_errors + self.overflow_errors
+    }
+}
+
+/// Splits a UART byte stream into NMEA sentences. A `$` starts a new sentence and a line ending
+/// finishes it, anything outside of a sentence is ignored. Only sentences with a valid checksum
+/// are returned.
+#[derive(Debug, Default)]
+pub struct NmeaFramer {
+    // without the line ending
+    sentence: String<{ MAX_SENTENCE_LENGTH - 2 }>,
+    in_sentence: bool,
+    stats: FramerStats,
+}
+
+impl NmeaFramer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stats(&self) -> FramerStats {
+        self.stats
+    }
+
+    /// Returns the sentence without its line ending once the line ending has been pushed
+    pub fn push(&mut self, byte: u8) -> Option<&str> {
+        match byte {
+            b'$' => {
+                if self.in_sentence {
+                    self.stats.framing_errors += 1;
+                }
+                self.sentence.clear();
+                self.sentence.push('$').ok();
+                self.in_sentence = true;
+            }
+            _ if !self.in_sentence => {}
+            b'\r' | b'\n' => {
+                self.in_sentence = false;
+                if self.check() {
+                    return Some(self.sentence.as_str());
+                }
+            }
+            b' '..=b'~' => {
+                if self.sentence.push(byte as char).is_err() {
+                    self.stats.overflow_errors += 1;
+                    self.in_sentence = false;
+                }
+            }
+            _ => {
+                self.stats.framing_errors += 1;
+                self.in_sentence = false;
+            }
+        }
+        None
+    }
+
+    /// Pushes bytes until a sentence is complete. Returns how many bytes were used and the
+    /// sentence, call again with the rest of the chunk.
+    pub fn push_slice(&mut self, bytes: &[u8]) -> (usize, Option<&str>) {
+        for (i, &byte) in bytes.iter().enumerate() {
+            if self.push(byte).is_some() {
+                return (i + 1, Some(self.sentence.as_str()));
+            }
+        }
+        (bytes.len(), None)
+    }
+
+    /// The sentence returned by the last `push`
+    pub(crate) fn last_sentence(&self) -> &str {
+        self.sentence.as_str()
+    }
+
+    fn check(&mut self) -> bool {
+        let checksum = self
+            .sentence
+            .rsplit_once('*')
+            .and_then(|(body, checksum)| Some((body, parse_checksum(checksum)?)));
+        match checksum {
+            Some((body, checksum)) if nmea_checksum(&body.as_bytes()[1..]) == checksum => {
+                self.stats.sentences += 1;
+                true
+            }
+            Some(_) => {
+                self.stats.checksum_errors += 1;
+                false
+            }
+            None => {
+                self.stats.framing_errors += 1;
+                false
+            }
+        }
+    }
+}
+
+/// XOR of the bytes between `$` and `*`
+pub fn nmea_checksum(body: &[u8]) -> u8 {
+    body.iter().fold(0, |checksum, byte| checksum ^ byte)
+}
+
+fn parse_checksum(hex: &str) -> Option<u8> {
+    if hex.len() != 2 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    u8::from_str_radix(hex, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::string::String;
+    use std::vec::Vec;
+
+    fn frame(framer: &mut NmeaFramer, stream: &[u8], chunk_size: usize) -> Vec<String> {
+        let mut sentences = Vec::new();
+        for mut chunk in stream.chunks(chunk_size) {
+            while !chunk.is_empty() {
+                let (used, sentence) = framer.push_slice(chunk);
+                if let Some(sentence) = sentence {
+                    sentences.push(String::from(sentence));
+                }
+                chunk = &chunk[used..];
+            }
+        }
+        sentences
+    }
+
+    #[test]
+    fn splits_sentences_across_reads() {
+        let stream = b"$GPGLL,4916.45,N,12311.12,W,225444,A*31\r\n$GPGGA,1,2*55\r\n$GP";
+        for chunk_size in [1, 7, stream.len()] {
+            let mut framer = NmeaFramer::new();
+            assert_eq!(
+                frame(&mut framer, stream, chunk_size),
+                ["$GPGLL,4916.45,N,12311.12,W,225444,A*31", "$GPGGA,1,2*55"]
+            );
+            assert_eq!(framer.stats().sentences, 2);
+        }
+    }
+
+    #[test]
+    fn resynchronizes_on_noisy_input() {
+        let mut stream = Vec::new();
+        stream.extend_from_slice(b"\xff\x00garbage*00\r\n");
+        // cut short, wrong checksum, no checksum, binary inside a sentence
+        stream.extend_from_slice(b"$GPGGA,1,$GPRMC,x*1F\r\n$GPGGA,1,2*56\r\n$GPGGA,1,2\r\n");
+        stream.extend_from_slice(b"$GPGGA,\x01*00\r\n");
+        stream.extend_from_slice(b"$GPGGA,");
+        stream.extend_from_slice(&[b'1'; 80]);
+        stream.extend_from_slice(b"*00\r\n$GPGGA,1,2*55\n");
+
+        let mut framer = NmeaFramer::new();
+        assert_eq!(
+            frame(&mut framer, &stream, 5),
+            ["$GPRMC,x*1F", "$GPGGA,1,2*55"]
+        );
+        assert_eq!(
+            framer.stats(),
+            FramerStats {
+                sentences: 2,
+                framing_errors: 3,
+                checksum_errors: 1,
+                overflow_errors: 1,
+            }
+        );
+    }
+}
@@ -0,0 +1,165 @@
+use crate::lsm6dsm::IMUData;
+use nalgebra::{Matrix3, Rotation3, UnitQuaternion, Vector3};
+
+// Vehicle frame: +X points towards the nose, +Y and +Z complete a right handed frame.
+// A stationary accelerometer measures the reaction to gravity, so on the pad it reads +1g
+// along the vehicle's +X. With the identity orientation the board axes are the vehicle axes
+// and the board's +X must point up. The VLF4 sits in the airframe with its -X towards the
+// nose, which is `NOSE_NEG_X`.
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Axis {
+    PosX,
+    NegX,
+    PosY,
+    NegY,
+    PosZ,
+    NegZ,
+}
+
+impl Axis {
+    pub const ALL: [Self; 6] = [
+        Self::PosX,
+        Self::NegX,
+        Self::PosY,
+        Self::NegY,
+        Self::PosZ,
+        Self::NegZ,
+    ];
+
+    const fn vector(&self) -> [f32; 3] {
+        match self {
+            Self::PosX => [1.0, 0.0, 0.0],
+            Self::NegX => [-1.0, 0.0, 0.0],
+            Self::PosY => [0.0, 1.0, 0.0],
+            Self::NegY => [0.0, -1.0, 0.0],
+            Self::PosZ => [0.0, 0.0, 1.0],
+            Self::NegZ => [0.0, 0.0, -1.0],
+        }
+    }
+}
+
+/// Rotation from the board frame to the vehicle frame
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq)]
+pub struct MountingOrientation {
+    /// Row major, `vehicle = matrix * board`
+    matrix: [[f32; 3]; 3],
+}
+
+impl MountingOrientation {
+    pub const IDENTITY: Self = Self {
+        matrix: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+    };
+
+    /// Board -X towards the nose, a half turn about Z
+    pub const NOSE_NEG_X: Self = Self {
+        matrix: [[-1.0, 0.0, 0.0], [0.0, -1.0, 0.0], [0.0, 0.0, 1.0]],
+    };
+
+    /// `board_x` and `board_y` are the vehicle axes the board's +X and +Y axes point along.
+    /// Returns `None` if they are not perpendicular, which leaves 24 valid orientations.
+    pub const fn axis_aligned(board_x: Axis, board_y: Axis) -> Option<Self> {
+        let x = board_x.vector();
+        let y = board_y.vector();
+        // z = x × y
+        let z = [
+            x[1] * y[2] - x[2] * y[1],
+            x[2] * y[0] - x[0] * y[2],
+            x[0] * y[1] - x[1] * y[0],
+        ];
+        if z[0] == 0.0 && z[1] == 0.0 && z[2] == 0.0 {
+            return None;
+        }
+        // the board axes are the columns
+        Some(Self {
+            matrix: [[x[0], y[0], z[0]], [x[1], y[1], z[1]], [x[2], y[2], z[2]]],
+        })
+    }
+
+    /// All 24 axis aligned orientations
+    pub fn all_axis_aligned() -> impl Iterator<Item = Self> {
+        Axis::ALL.into_iter().flat_map(|board_x| {
+            Axis::ALL
+                .into_iter()
+                .filter_map(move |board_y| Self::axis_aligned(board_x, board_y))
+        })
+    }
+
+    /// `rotation` takes vectors from the board frame to the vehicle frame
+    pub fn from_quaternion(rotation: UnitQuaternion<f32>) -> Self {
+        let matrix = rotation.to_rotation_matrix().into_inner();
+        Self {
+            matrix: [0, 1, 2].map(|row| [0, 1, 2].map(|column| matrix[(row, column)])),
+        }
+    }
+
+    pub fn to_quaternion(&self) -> UnitQuaternion<f32> {
+        // closed form, the iterative `from_matrix` doesn't converge for half turns
+        UnitQuaternion::from_rotation_matrix(&Rotation3::from_matrix_unchecked(self.to_matrix()))
+    }
+
+    pub fn to_matrix(&self) -> Matrix3<f32> {
+        Matrix3::from_fn(|row, column| self.matrix[row][column])
+    }
+
+    pub fn rotate(&self, board: &[f32; 3]) -> [f32; 3] {
+        (self.to_matrix() * Vector3::from(*board)).into()
+    }
+
+    pub fn apply(&self, data: &mut IMUData) {
+        data.acc = self.rotate(&data.acc);
+        data.gyro = self.rotate(&data.gyro);
+    }
+}
+
+impl Default for MountingOrientation {
+    fn default() -> Self {
+        Self::IDENTITY
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn axis_aligned_orientations_are_rotations() {
+        let all: std::vec::Vec<_> = MountingOrientation::all_axis_aligned().collect();
+        assert_eq!(all.len(), 24);
+        for (i, orientation) in all.iter().enumerate() {
+            let matrix = orientation.to_matrix();
+            assert_eq!(matrix.determinant(), 1.0);
+            assert_eq!(matrix * matrix.transpose(), Matrix3::identity());
+            assert!(all[..i].iter().all(|other| other != orientation));
+        }
+        assert_eq!(
+            MountingOrientation::axis_aligned(Axis::NegX, Axis::NegY),
+            Some(MountingOrientation::NOSE_NEG_X)
+        );
+        assert_eq!(
+            MountingOrientation::axis_aligned(Axis::PosX, Axis::NegX),
+            None
+        );
+        // the board's -X ends up along the nose
+        assert_eq!(
+            MountingOrientation::NOSE_NEG_X.rotate(&[-9.81, 0.0, 0.0]),
+            [9.81, 0.0, 0.0]
+        );
+    }
+
+    #[test]
+    fn quaternion_round_trip() {
+        let rotation = UnitQuaternion::from_euler_angles(0.3f32, -1.1, 2.0);
+        let orientation = MountingOrientation::from_quaternion(rotation);
+        assert!(orientation.to_quaternion().angle_to(&rotation) < 1e-5);
+        let v = [1.0, -2.0, 0.5];
+        let expected = rotation * Vector3::from(v);
+        let rotated = Vector3::from(orientation.rotate(&v));
+        assert!((rotated - expected).norm() < 1e-5);
+
+        for orientation in MountingOrientation::all_axis_aligned() {
+            let round_trip = MountingOrientation::from_quaternion(orientation.to_quaternion());
+            assert!((round_trip.to_matrix() - orientation.to_matrix()).norm() < 1e-6);
+        }
+    }
+}
@@ -0,0 +1,286 @@
+// Pairs PPS edges with the UTC second they mark. The edge is timestamped precisely but says
+// nothing about which second it is, the time sentences (NMEA or UBX) name the second but arrive
+// late and jittery. Depending on the receiver a sentence follows the pulse it describes or
+// announces the next one. An edge and a sentence are only paired when they are close enough
+// in the right order, and every pair is checked against the previous one.
+// Local times are ns on the `embassy_time::Instant` timebase.
+
+const NS_PER_SECOND: i64 = 1_000_000_000;
+
+/// When the time sentence of a second arrives relative to its pulse
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SentenceOrder {
+    /// The sentence reports the second of the pulse before it, NMEA and NAV-PVT on u-blox
+    AfterPulse,
+    /// The sentence announces the second of the next pulse, e.g. TIM-TP
+    BeforePulse,
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AssociationConfig {
+    pub order: SentenceOrder,
+    /// Longest time between a pulse and its sentence, ns
+    pub max_delay_ns: i64,
+}
+
+impl AssociationConfig {
+    /// u-blox NMEA and NAV-PVT, which arrive well within a second of the pulse
+    pub const DEFAULT: Self = Self {
+        order: SentenceOrder::AfterPulse,
+        max_delay_ns: 900_000_000,
+    };
+}
+
+impl Default for AssociationConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// How a paired second follows the previous one
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Progression {
+    /// Nothing to compare with
+    First,
+    /// One second after the previous pair
+    Next,
+    /// Whole seconds were missed, the time still agrees with the edges
+    Gap { missed: u32 },
+    /// The second doesn't match the time between the edges, a leap second, a receiver time
+    /// correction or a wrong pairing
+    Leap { expected: i64 },
+}
+
+/// A PPS edge and the second it marks
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PpsTime {
+    pub edge_ns: i64,
+    pub unix_time: i64,
+    pub progression: Progression,
+}
+
+#[derive(defmt::Format, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AssociationStats {
+    pub paired: u32,
+    /// Edges without a sentence
+    pub unmatched_edges: u32,
+    /// Sentences without an edge, or too far from it
+    pub unmatched_sentences: u32,
+    pub gaps: u32,
+    pub leaps: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct PpsAssociator {
+    config: AssociationConfig,
+    /// Edge waiting for its sentence
+    pending_edge: Option<i64>,
+    /// Sentence waiting for its edge, as (received, unix time)
+    pending_sentence: Option<(i64, i64)>,
+    /// Second named by the last sentence, the receiver repeats it in every message of the second
+    last_sentence: Option<i64>,
+    last: Option<PpsTime>,
+    stats: AssociationStats,
+}
+
+impl PpsAssociator {
+    pub const fn new(config: AssociationConfig) -> Self {
+        Self {
+            config,
+            pending_edge: None,
+            pending_sentence: None,
+            last_sentence: None,
+            last: None,
+            stats: AssociationStats {
+                paired: 0,
+                unmatched_edges: 0,
+                unmatched_sentences: 0,
+                gaps: 0,
+                leaps: 0,
+            },
+        }
+    }
+
+    pub fn stats(&self) -> AssociationStats {
+        self.stats
+    }
+
+    /// Last paired edge
+    pub fn last(&self) -> Option<PpsTime> {
+        self.last
+    }
+
+    /// Feeds a PPS edge at local time `edge_ns`
+    pub fn on_pulse(&mut self, edge_ns: i64) -> Option<PpsTime> {
+        match self.config.order {
+            SentenceOrder::AfterPulse => {
+                if self.pending_edge.replace(edge_ns).is_some() {
+                    self.stats.unmatched_edges += 1;
+                }
+                None
+            }
+            SentenceOrder::BeforePulse => {
+                let Some((received_ns, unix_time)) = self.pending_sentence.take() else {
+                    self.stats.unmatched_edges += 1;
+                    return None;
+                };
+                if !self.in_window(received_ns, edge_ns) {
+                    self.stats.unmatched_sentences += 1;
+                    self.stats.unmatched_edges += 1;
+                    return None;
+                }
+                Some(self.pair(edge_ns, unix_time))
+            }
+        }
+    }
+
+    /// Feeds a time sentence received at local time `received_ns` that names `unix_time`
+    pub fn on_sentence(&mut self, received_ns: i64, unix_time: i64) -> Option<PpsTime> {
+        if self.last_sentence.replace(unix_time) == Some(unix_time) {
+            return None;
+        }
+        match self.config.order {
+            SentenceOrder::AfterPulse => {
+                let Some(edge_ns) = self.pending_edge.take() else {
+                    self.stats.unmatched_sentences += 1;
+                    return None;
+                };
+                if !self.in_window(edge_ns, received_ns) {
+                    self.stats.unmatched_sentences += 1;
+                    self.stats.unmatched_edges += 1;
+                    return None;
+                }
+                Some(self.pair(edge_ns, unix_time))
+            }
+            SentenceOrder::BeforePulse => {
+                if self
+                    .pending_sentence
+                    .replace((received_ns, unix_time))
+                    .is_some()
+                {
+                    self.stats.unmatched_sentences += 1;
+                }
+                None
+            }
+        }
+    }
+
+    fn in_window(&self, first_ns: i64, second_ns: i64) -> bool {
+        (0..=self.config.max_delay_ns).contains(&(second_ns - first_ns))
+    }
+
+    fn pair(&mut self, edge_ns: i64, unix_time: i64) -> PpsTime {
+        let progression = match self.last {
+            None => Progression::First,
+            Some(last) => {
+                // rounding tolerates up to half a second of drift over the interval
+                let elapsed =
+                    (edge_ns - last.edge_ns + NS_PER_SECOND / 2).div_euclid(NS_PER_SECOND);
+                let expected = last.unix_time + elapsed;
+                if unix_time != expected || elapsed <= 0 {
+                    self.stats.leaps += 1;
+                    Progression::Leap { expected }
+                } else if elapsed > 1 {
+                    self.stats.gaps += 1;
+                    Progression::Gap {
+                        missed: (elapsed - 1) as u32,
+                    }
+                } else {
+                    Progression::Next
+                }
+            }
+        };
+        self.stats.paired += 1;
+        let time = PpsTime {
+            edge_ns,
+            unix_time,
+            progression,
+        };
+        self.last = Some(time);
+        time
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MS: i64 = 1_000_000;
+
+    // edges every second from 10s, the second k is unix time 1000 + k
+    fn edge(k: i64) -> i64 {
+        10_000 * MS + k * NS_PER_SECOND
+    }
+
+    fn associator(order: SentenceOrder) -> PpsAssociator {
+        PpsAssociator::new(AssociationConfig {
+            order,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn sentence_after_pulse() {
+        let mut associator = associator(SentenceOrder::AfterPulse);
+        // a sentence before the first edge has nothing to pair with
+        assert_eq!(associator.on_sentence(edge(0) - 300 * MS, 999), None);
+
+        let mut paired = std::vec::Vec::new();
+        for k in 0..4 {
+            assert_eq!(associator.on_pulse(edge(k)), None);
+            // several messages per second name the same second
+            for delay in [150, 350, 550] {
+                paired.extend(associator.on_sentence(edge(k) + delay * MS, 1000 + k));
+            }
+        }
+        let seconds: std::vec::Vec<_> = paired.iter().map(|p| (p.edge_ns, p.unix_time)).collect();
+        assert_eq!(seconds, [0, 1, 2, 3].map(|k| (edge(k), 1000 + k)));
+        assert_eq!(paired[0].progression, Progression::First);
+        assert!(
+            paired[1..]
+                .iter()
+                .all(|p| p.progression == Progression::Next)
+        );
+
+        // sentence lost, then one that arrives too late
+        associator.on_pulse(edge(4));
+        associator.on_pulse(edge(5));
+        assert_eq!(associator.on_sentence(edge(5) + 950 * MS, 1005), None);
+        assert_eq!(associator.on_pulse(edge(6)), None);
+        let time = associator.on_sentence(edge(6) + 100 * MS, 1006).unwrap();
+        assert_eq!(time.progression, Progression::Gap { missed: 2 });
+        assert_eq!(
+            associator.stats(),
+            AssociationStats {
+                paired: 5,
+                unmatched_edges: 2,
+                unmatched_sentences: 2,
+                gaps: 1,
+                leaps: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn sentence_before_pulse() {
+        let mut associator = associator(SentenceOrder::BeforePulse);
+        // an edge before any sentence
+        assert_eq!(associator.on_pulse(edge(0)), None);
+        for k in 1..4 {
+            assert_eq!(associator.on_sentence(edge(k) - 400 * MS, 1000 + k), None);
+            assert_eq!(associator.on_sentence(edge(k) - 200 * MS, 1000 + k), None);
+            let time = associator.on_pulse(edge(k)).unwrap();
+            assert_eq!((time.edge_ns, time.unix_time), (edge(k), 1000 + k));
+        }
+        // the receiver steps its time by a second
+        associator.on_sentence(edge(4) - 300 * MS, 1005);
+        let time = associator.on_pulse(edge(4)).unwrap();
+        assert_eq!(time.progression, Progression::Leap { expected: 1004 });
+        // and carries on from there
+        associator.on_sentence(edge(5) - 300 * MS, 1006);
+        let time = associator.on_pulse(edge(5)).unwrap();
+        assert_eq!(time.progression, Progression::Next);
+        assert_eq!(associator.stats().unmatched_edges, 1);
+        assert_eq!(associator.stats().leaps, 1);
+    }
+}
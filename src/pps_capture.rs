@@ -0,0 +1,133 @@
+// Converts timer input captures of the PPS edge into local times. The capture timer is too
+// short to count whole seconds, so every edge is also located roughly with `Instant::now()`,
+// which sets how many times the counter wrapped since the previous edge. Edge-to-edge
+// intervals then have the resolution of the capture timer instead of the `Instant` tick.
+// Both timers must run from the same oscillator.
+
+/// Largest distance between the capture timeline and `Instant` before starting over, ns
+const RESYNC_NS: i64 = 10_000;
+
+/// Read right after a capture
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CapturedEdge {
+    /// Counter value latched by the edge
+    pub capture: u32,
+    /// Counter value when the capture was read
+    pub counter: u32,
+    /// Local time when the capture was read, ns on the `Instant` timebase
+    pub now_ns: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct CaptureTimeline {
+    tick_hz: i64,
+    counter_mask: u32,
+    /// Local time the tick count starts from, and ticks up to the last edge
+    base: Option<(i64, i64)>,
+    last_capture: u32,
+    resyncs: u32,
+}
+
+impl CaptureTimeline {
+    /// For a counter that is `counter_bits` wide and ticks at `tick_hz`. The capture must be
+    /// read before the counter wraps, within 4ms for 16 bits at 16MHz, a later read puts the
+    /// edge whole wraps too late.
+    pub const fn new(tick_hz: u32, counter_bits: u32) -> Self {
+        Self {
+            tick_hz: tick_hz as i64,
+            counter_mask: if counter_bits >= 32 {
+                u32::MAX
+            } else {
+                (1 << counter_bits) - 1
+            },
+            base: None,
+            last_capture: 0,
+            resyncs: 0,
+        }
+    }
+
+    /// Times the timeline didn't agree with `Instant` and started over
+    pub fn resyncs(&self) -> u32 {
+        self.resyncs
+    }
+
+    /// Local time of the edge, ns on the `Instant` timebase
+    pub fn edge_ns(&mut self, edge: &CapturedEdge) -> i64 {
+        let latency = (edge.counter.wrapping_sub(edge.capture) & self.counter_mask) as i64;
+        let coarse_ns = edge.now_ns - self.ticks_to_ns(latency);
+
+        if let Some((base_ns, ticks)) = self.base {
+            let period = self.counter_mask as i64 + 1;
+            let since_last =
+                (edge.capture.wrapping_sub(self.last_capture) & self.counter_mask) as i64;
+            let last_ns = base_ns + self.ticks_to_ns(ticks);
+            let coarse_ticks = self.ns_to_ticks(coarse_ns - last_ns);
+            let wraps = (coarse_ticks - since_last + period / 2).div_euclid(period);
+            let ticks = ticks + wraps * period + since_last;
+            let edge_ns = base_ns + self.ticks_to_ns(ticks);
+            if (edge_ns - coarse_ns).abs() <= RESYNC_NS {
+                self.base = Some((base_ns, ticks));
+                self.last_capture = edge.capture;
+                return edge_ns;
+            }
+            self.resyncs += 1;
+        }
+
+        self.base = Some((coarse_ns, 0));
+        self.last_capture = edge.capture;
+        coarse_ns
+    }
+
+    fn ticks_to_ns(&self, ticks: i64) -> i64 {
+        (ticks as i128 * 1_000_000_000 / self.tick_hz as i128) as i64
+    }
+
+    fn ns_to_ticks(&self, ns: i64) -> i64 {
+        (ns as i128 * self.tick_hz as i128 / 1_000_000_000) as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK_HZ: i64 = 16_000_000;
+
+    // edge at `edge_ns`, read `latency_ns` later with a 1µs `Instant`
+    fn capture(edge_ns: i64, latency_ns: i64) -> CapturedEdge {
+        let ticks = |ns: i64| (ns as i128 * TICK_HZ as i128 / 1_000_000_000) as u32 & 0xFFFF;
+        let now_ns = edge_ns + latency_ns;
+        CapturedEdge {
+            capture: ticks(edge_ns),
+            counter: ticks(now_ns),
+            now_ns: now_ns / 1_000 * 1_000,
+        }
+    }
+
+    #[test]
+    fn resolves_edges_to_timer_ticks() {
+        let mut timeline = CaptureTimeline::new(TICK_HZ as u32, 16);
+        let first = 2_000_000_000;
+        let first_ns = timeline.edge_ns(&capture(first, 35_000));
+        assert!((first_ns - first).abs() <= 1_000);
+
+        // 50ppm fast, a 62.5ns tick resolves that where `Instant` can't
+        let latencies = [120_000, 3_000, 900_000, 40_000];
+        for (k, latency) in latencies.into_iter().enumerate() {
+            let edge = first + (k as i64 + 1) * 1_000_050_000;
+            let edge_ns = timeline.edge_ns(&capture(edge, latency));
+            assert!(((edge_ns - first_ns) - (edge - first)).abs() <= 63);
+        }
+        // a gap of ten seconds
+        let edge = first + 15 * 1_000_050_000;
+        let edge_ns = timeline.edge_ns(&capture(edge, 10_000));
+        assert!(((edge_ns - first_ns) - (edge - first)).abs() <= 63);
+        assert_eq!(timeline.resyncs(), 0);
+
+        // read after the counter wrapped, the edge looks one wrap late and it's up to the
+        // time discipline to reject it
+        let late = edge + 1_000_050_000;
+        let edge_ns = timeline.edge_ns(&capture(late, 5_000_000));
+        assert!(((edge_ns - first_ns) - (late - first) - 4_096_000).abs() <= 63);
+    }
+}
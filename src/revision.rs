@@ -0,0 +1,98 @@
+// PC4 is connected to curr_ref (2.5V) on r1 and to the green led (0V) on r2.
+// Raw readings are 16 bit with a 3.3V reference.
+
+// anything in between is ambiguous
+const R2_MAX: u16 = 8_000; // ~0.4V
+const R1_MIN: u16 = 40_000; // ~2.0V
+const R1_MAX: u16 = 58_000; // ~2.9V
+// readings this close to the nominal voltage are high confidence
+const R2_CONFIDENT_MAX: u16 = 3_000; // ~0.15V
+const R1_CONFIDENT: (u16, u16) = (45_500, 53_500); // 2.5V ±0.15V
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BoardRevision {
+    R1,
+    R2,
+    Unknown,
+}
+
+impl BoardRevision {
+    /// The revision selected with the `vlf4r1` / `vlf4r2` feature
+    pub const fn compiled() -> Self {
+        if cfg!(feature = "vlf4r1") {
+            Self::R1
+        } else if cfg!(feature = "vlf4r2") {
+            Self::R2
+        } else {
+            Self::Unknown
+        }
+    }
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Confidence {
+    /// Close to the nominal voltage of the revision
+    High,
+    /// Within the band of the revision but away from its nominal voltage, or unknown
+    Low,
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RevisionDetection {
+    pub revision: BoardRevision,
+    pub confidence: Confidence,
+    /// PC4 ADC reading
+    pub raw: u16,
+}
+
+impl RevisionDetection {
+    pub const fn from_raw(raw: u16) -> Self {
+        let (revision, confident) = match raw {
+            0..=R2_MAX => (BoardRevision::R2, raw <= R2_CONFIDENT_MAX),
+            R1_MIN..=R1_MAX => (
+                BoardRevision::R1,
+                raw >= R1_CONFIDENT.0 && raw <= R1_CONFIDENT.1,
+            ),
+            _ => (BoardRevision::Unknown, false),
+        };
+        Self {
+            revision,
+            confidence: if confident {
+                Confidence::High
+            } else {
+                Confidence::Low
+            },
+            raw,
+        }
+    }
+
+    /// Whether the detected revision contradicts the compiled one. An unknown reading
+    /// never conflicts.
+    pub const fn conflicts_with(&self, revision: BoardRevision) -> bool {
+        matches!(
+            (self.revision, revision),
+            (BoardRevision::R1, BoardRevision::R2) | (BoardRevision::R2, BoardRevision::R1)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_readings() {
+        let r1 = RevisionDetection::from_raw(49_600);
+        assert_eq!(r1.revision, BoardRevision::R1);
+        assert_eq!(r1.confidence, Confidence::High);
+        assert_eq!(
+            RevisionDetection::from_raw(42_000).confidence,
+            Confidence::Low
+        );
+        assert_eq!(RevisionDetection::from_raw(100).revision, BoardRevision::R2);
+        let unknown = RevisionDetection::from_raw(25_000);
+        assert_eq!(unknown.revision, BoardRevision::Unknown);
+        assert!(!unknown.conflicts_with(BoardRevision::R1));
+        assert!(r1.conflicts_with(BoardRevision::R2));
+    }
+}
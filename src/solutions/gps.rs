@@ -2,131 +2,159 @@
 #![no_main]
 #![feature(impl_trait_in_assoc_type)]
 
-use crate::clock::{verify_revision, vlf4_clock};
-use chrono::{TimeZone as _, Utc};
+use core::cell::RefCell;
 use cortex_m::singleton;
 use defmt::*;
 use embassy_executor::Spawner;
-use embassy_stm32::exti::ExtiInput;
-use embassy_stm32::gpio::Pull;
 use embassy_stm32::gpio::{Level, Output, Speed};
-#[cfg(feature = "vlf4r1")]
-use embassy_stm32::peripherals::{EXTI5, PA0, PA1, PB5, UART4};
-#[cfg(feature = "vlf4r2")]
-use embassy_stm32::peripherals::{EXTI12, PA2, PA3, PD12, USART2};
-use embassy_stm32::usart::{BufferedUart, Config as UartConfig};
-use embassy_stm32::{Peri, bind_interrupts, usart};
-use embassy_sync::blocking_mutex::raw::NoopRawMutex;
-use embassy_sync::signal::Signal;
+use embassy_stm32::usart::Config as UartConfig;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 use embassy_time::{Duration, Instant, Timer};
 use embedded_io_async::Read;
-use heapless::String;
-use nmea::Nmea;
-use nmea::sentences::FixType;
+use nmea::{Nmea, SentenceType};
+use vlf4::board::{Board, GpsPort, PPS_CAPTURE_BITS, PPS_CAPTURE_FREQUENCY, PpsInput};
+use vlf4::gps_config::{
+    COMMON_BAUD_RATES, DEFAULT_BAUD_RATE, GpsConfig, configure_gps, detect_baud_rate,
+};
+use vlf4::gps_demux::{GpsDemux, GpsPacket};
+use vlf4::gps_fix::{GpsFix, GpsFixWatch};
+use vlf4::gps_satellites::{SatelliteStatus, SatelliteStatusWatch};
+use vlf4::pps_association::{AssociationConfig, PpsAssociator, PpsTime, Progression};
+use vlf4::pps_capture::CaptureTimeline;
+use vlf4::time_discipline::{SharedTimeDiscipline, instant_ns};
+use vlf4::timing::fix_unix_time;
+use vlf4::ubx::UbxMessage;
 
 use {defmt_rtt as _, panic_probe as _};
 
-#[path = "../clock.rs"]
-mod clock;
+/// UTC for every task, disciplined by the PPS edges
+static UTC_CLOCK: SharedTimeDiscipline = SharedTimeDiscipline::new();
+/// Pairs the PPS edges with the time reported by the receiver, fed by both tasks
+static PPS_ASSOCIATOR: Mutex<CriticalSectionRawMutex, RefCell<PpsAssociator>> =
+    Mutex::new(RefCell::new(PpsAssociator::new(AssociationConfig::DEFAULT)));
+
+/// Latest navigation solution for logging, telemetry and recovery
+static GPS_FIX: GpsFixWatch<4> = GpsFixWatch::new();
+/// Satellites in view and in use, to check the antenna on the pad
+static SATELLITES: SatelliteStatusWatch<4> = SatelliteStatusWatch::new();
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
-    let p = embassy_stm32::init(vlf4_clock());
-    verify_revision(p.ADC1, p.PC4);
+    let board = Board::init();
     info!("Hello world");
 
     // red led
     // high -> led on; low -> led off
-    // change the default feature in Cargo.toml
-    #[cfg(feature = "vlf4r1")]
-    let led = Output::new(p.PB9, Level::Low, Speed::Low);
-    #[cfg(feature = "vlf4r2")]
-    let led = Output::new(p.PD10, Level::Low, Speed::Low);
-
-    let nmea_unix_time_signal =
-        singleton!(: Signal::<NoopRawMutex, (Instant, i64)> = Signal::new()).unwrap();
-
-    #[cfg(feature = "vlf4r1")]
-    spawner.spawn(nmea_task(p.UART4, p.PA1, p.PA0, nmea_unix_time_signal).unwrap());
-    #[cfg(feature = "vlf4r2")]
-    spawner.spawn(nmea_task(p.USART2, p.PA3, p.PA2, nmea_unix_time_signal).unwrap());
-
-    #[cfg(feature = "vlf4r1")]
-    spawner.spawn(pps_task(led, p.PB5, p.EXTI5, nmea_unix_time_signal).unwrap());
-    #[cfg(feature = "vlf4r2")]
-    spawner.spawn(pps_task(led, p.PD12, p.EXTI12, nmea_unix_time_signal).unwrap());
+    let led = Output::new(board.status_led, Level::Low, Speed::Low);
+
+    spawner.spawn(nmea_task(board.gps).unwrap());
+    spawner.spawn(pps_task(led, board.pps).unwrap());
+    spawner.spawn(fix_log_task().unwrap());
+    spawner.spawn(satellite_log_task().unwrap());
 }
 
 #[embassy_executor::task]
-async fn nmea_task(
-    #[cfg(feature = "vlf4r1")] usart: Peri<'static, UART4>,
-    #[cfg(feature = "vlf4r2")] usart: Peri<'static, USART2>,
-
-    #[cfg(feature = "vlf4r1")] rx: Peri<'static, PA1>,
-    #[cfg(feature = "vlf4r2")] rx: Peri<'static, PA3>,
-
-    #[cfg(feature = "vlf4r1")] tx: Peri<'static, PA0>,
-    #[cfg(feature = "vlf4r2")] tx: Peri<'static, PA2>,
-
-    nmea_unix_time_signal: &'static Signal<NoopRawMutex, (Instant, i64)>,
-) {
-    #[cfg(feature = "vlf4r1")]
-    bind_interrupts!(struct Irqs {
-        UART4 => usart::BufferedInterruptHandler<UART4>;
-    });
-    #[cfg(feature = "vlf4r2")]
-    bind_interrupts!(struct Irqs {
-        USART2 => usart::BufferedInterruptHandler<USART2>;
-    });
-
+async fn nmea_task(gps: GpsPort) {
     let tx_buf = singleton!(: [u8; 64] = [0; 64]).unwrap();
-    let rx_buf = singleton!(: [u8; 64] = [0; 64]).unwrap();
+    // NAV-PVT alone is 100 bytes
+    let rx_buf = singleton!(: [u8; 512] = [0; 512]).unwrap();
     let mut config = UartConfig::default();
-    config.baudrate = 9600;
-    let mut uart = BufferedUart::new(usart, rx, tx, tx_buf, rx_buf, Irqs, config).unwrap();
+    config.baudrate = DEFAULT_BAUD_RATE;
+    let mut uart = gps.into_buffered_uart(tx_buf, rx_buf, config).unwrap();
+
+    // the receiver keeps its configuration while it has backup power
+    let baud_rate =
+        match detect_baud_rate(&mut uart, &COMMON_BAUD_RATES, Duration::from_millis(1200)).await {
+            Ok(Some(baud_rate)) => {
+                info!("GPS found at {} baud", baud_rate);
+                baud_rate
+            }
+            Ok(None) => {
+                warn!(
+                    "No answer from the GPS, assuming {} baud",
+                    DEFAULT_BAUD_RATE
+                );
+                DEFAULT_BAUD_RATE
+            }
+            Err(e) => {
+                error!("Error detecting the GPS baud rate: {}", e);
+                DEFAULT_BAUD_RATE
+            }
+        };
+
+    match configure_gps(&mut uart, baud_rate, &GpsConfig::default()).await {
+        Ok(report) if report.complete() => info!("GPS configured: {}", report),
+        Ok(report) => warn!("GPS partially configured: {}", report),
+        Err(e) => error!("Error configuring GPS: {}", e),
+    }
 
     let mut buffer = [0; 64];
-    let mut sentence = String::<84>::new();
+    let mut demux = GpsDemux::new();
+    let mut errors = 0;
     let mut nmea = Nmea::default();
+    let fix_sender = GPS_FIX.sender();
+    // NAV-PVT is preferred, the NMEA fix is only published for receivers without it
+    let mut nav_pvt_seen = false;
+    let mut satellites = SatelliteStatus::new();
+    let satellite_sender = SATELLITES.sender();
 
     loop {
         match uart.read(&mut buffer).await {
             Ok(length) => {
-                for i in 0..length {
-                    if buffer[i] == b'$' {
-                        sentence.clear();
-                    }
-                    sentence.push(buffer[i] as char).ok();
-
-                    if buffer[i] == b'\n' || sentence.len() == sentence.capacity() {
-                        let parse_result = nmea.parse(sentence.as_str());
-
-                        if let Err(e) = parse_result {
-                            warn!(
-                                "Parse error: {:?}, sentence: {}",
-                                Debug2Format(&e),
-                                sentence.as_str()
-                            );
-                        } else {
-                            info!("Parsed: {}", sentence.as_str());
-                        }
-
-                        if nmea.fix_type != None
-                            && nmea.fix_type != Some(FixType::Invalid)
-                            && let Some(date) = nmea.fix_date
-                            && let Some(time) = nmea.fix_time
-                        {
-                            let datetime = date.and_time(time);
-                            let datetime = Utc.from_utc_datetime(&datetime);
-                            nmea_unix_time_signal.signal((Instant::now(), datetime.timestamp()));
-                        }
-
-                        sentence.clear();
-                        for j in (i + 1)..length {
-                            sentence.push(buffer[j] as char).ok();
+                let mut bytes = &buffer[..length];
+                while !bytes.is_empty() {
+                    let (used, packet) = demux.push_slice(bytes);
+                    bytes = &bytes[used..];
+                    match packet {
+                        Some(GpsPacket::Nmea(sentence)) => {
+                            if satellites.update(sentence) {
+                                satellite_sender.send(satellites.clone());
+                            }
+                            match nmea.parse(sentence) {
+                                Ok(SentenceType::GGA) if !nav_pvt_seen => fix_sender
+                                    .send(GpsFix::from_nmea(&nmea, instant_ns(Instant::now()))),
+                                Ok(_) => debug!("Parsed: {}", sentence),
+                                Err(e) => warn!(
+                                    "Parse error: {:?}, sentence: {}",
+                                    Debug2Format(&e),
+                                    sentence
+                                ),
+                            }
+
+                            if let Some(unix_time) = fix_unix_time(&nmea) {
+                                on_time_sentence(unix_time);
+                            }
                         }
+                        Some(GpsPacket::Ubx(frame)) => match UbxMessage::parse(&frame) {
+                            Some(UbxMessage::NavPvt(pvt)) => {
+                                let mut fix =
+                                    GpsFix::from_nav_pvt(&pvt, instant_ns(Instant::now()));
+                                // from the last GSA
+                                fix.hdop = nmea.hdop;
+                                fix.vdop = nmea.vdop;
+                                fix_sender.send(fix);
+                                nav_pvt_seen = true;
+                                if let Some(unix_time) = pvt.unix_time().filter(|_| pvt.fix_ok()) {
+                                    on_time_sentence(unix_time);
+                                }
+                            }
+                            Some(message) => info!("UBX: {}", message),
+                            None => debug!("Unhandled UBX {:x} {:x}", frame.class, frame.id),
+                        },
+                        None => {}
                     }
                 }
+
+                let total = demux.nmea.stats().errors() + demux.ubx.stats().errors();
+                if total != errors {
+                    errors = total;
+                    warn!(
+                        "Dropped GPS data: {} {}",
+                        demux.nmea.stats(),
+                        demux.ubx.stats()
+                    );
+                }
             }
             Err(e) => {
                 error!("Error reading from UART: {}", e);
@@ -136,28 +164,70 @@ async fn nmea_task(
 }
 
 #[embassy_executor::task]
-async fn pps_task(
-    mut led: Output<'static>,
-
-    #[cfg(feature = "vlf4r1")] pin: Peri<'static, PB5>,
-    #[cfg(feature = "vlf4r2")] pin: Peri<'static, PD12>,
+async fn fix_log_task() {
+    let mut fixes = GPS_FIX.receiver().unwrap();
+    loop {
+        let fix = fixes.changed().await;
+        info!("GPS fix: {}", fix);
+    }
+}
 
-    #[cfg(feature = "vlf4r1")] exti: Peri<'static, EXTI5>,
-    #[cfg(feature = "vlf4r2")] exti: Peri<'static, EXTI12>,
+#[embassy_executor::task]
+async fn satellite_log_task() {
+    let mut statuses = SATELLITES.receiver().unwrap();
+    let mut health = None;
+    loop {
+        let summary = statuses.changed().await.summary();
+        if health != Some(summary.health) {
+            health = Some(summary.health);
+            info!("GPS health {}: {}", summary.health, summary);
+        } else {
+            debug!("Satellites: {}", summary);
+        }
+    }
+}
 
-    nmea_unix_time_signal: &'static Signal<NoopRawMutex, (Instant, i64)>,
-) {
-    let mut pps = ExtiInput::new(pin, exti, Pull::None);
+#[embassy_executor::task]
+async fn pps_task(mut led: Output<'static>, pps: PpsInput) {
+    let mut pps = pps.into_capture();
+    let mut timeline = CaptureTimeline::new(PPS_CAPTURE_FREQUENCY.0, PPS_CAPTURE_BITS);
 
     loop {
-        pps.wait_for_rising_edge().await;
-        if let Some((instant, unix_time)) = nmea_unix_time_signal.try_take()
-            && Instant::now() - instant < Duration::from_millis(800)
-        {
-            info!("Unix timestamp: {}", unix_time + 1);
-            led.set_high();
-            Timer::after_millis(200).await;
-            led.set_low();
+        let captured = pps.wait_for_edge().await;
+        let edge_ns = timeline.edge_ns(&captured);
+        let time = PPS_ASSOCIATOR.lock(|associator| associator.borrow_mut().on_pulse(edge_ns));
+        if let Some(time) = time {
+            discipline(time);
         }
+        led.set_high();
+        Timer::after_millis(200).await;
+        led.set_low();
+    }
+}
+
+/// The receiver reported the second `unix_time`
+fn on_time_sentence(unix_time: i64) {
+    let received_ns = instant_ns(Instant::now());
+    let time = PPS_ASSOCIATOR
+        .lock(|associator| associator.borrow_mut().on_sentence(received_ns, unix_time));
+    if let Some(time) = time {
+        discipline(time);
+    }
+}
+
+fn discipline(time: PpsTime) {
+    match time.progression {
+        Progression::Gap { missed } => warn!("Missed {} PPS seconds", missed),
+        Progression::Leap { expected } => warn!(
+            "GPS time jumped to {}, expected {}",
+            time.unix_time, expected
+        ),
+        Progression::First | Progression::Next => {}
+    }
+    if !UTC_CLOCK.on_pps(time.edge_ns, time.unix_time) {
+        warn!("PPS edge rejected: {}", UTC_CLOCK.stats());
+    }
+    if let Some(utc) = UTC_CLOCK.now_utc() {
+        info!("UTC: {}", utc);
     }
 }
@@ -2,18 +2,10 @@
 #![no_main]
 #![feature(impl_trait_in_assoc_type)]
 
-use crate::clock::{verify_revision, vlf4_clock};
-use crate::lsm6dsm::LSM6DSM;
-use biquad::{
-    Biquad as _, Coefficients, DirectForm2Transposed, Q_BUTTERWORTH_F32, ToHertz as _, Type,
-};
 use cortex_m::singleton;
 use defmt::*;
 use embassy_embedded_hal::shared_bus::asynch::spi::SpiDeviceWithConfig;
 use embassy_executor::Spawner;
-use embassy_stm32::Peri;
-use embassy_stm32::gpio::AnyPin;
-use embassy_stm32::peripherals::{DMA1_CH4, DMA1_CH5, PC10, PC11, PC12, SPI3};
 use embassy_stm32::spi::{Config as SpiConfig, Spi};
 use embassy_stm32::{
     gpio::{Level, Output, Speed},
@@ -21,106 +13,113 @@ use embassy_stm32::{
 };
 use embassy_sync::signal::Signal;
 use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex};
-use embassy_time::{Duration, Ticker, Timer};
+use embassy_time::Timer;
 use micromath::F32Ext;
-use nalgebra::Vector3;
+use vlf4::attitude::{AttitudeEstimator, AttitudeEstimatorConfig};
+use vlf4::board::{Board, ImuBus, ImuInterrupt};
+use vlf4::lsm6dsm::{FifoConfig, IMUData, InterruptConfig, InterruptRouting, LSM6DSM};
+use vlf4::orientation::MountingOrientation;
+use vlf4::tilt_trigger::TiltTrigger;
 
 use {defmt_rtt as _, panic_probe as _};
 
-#[path = "../clock.rs"]
-mod clock;
-#[path = "../lsm6dsm.rs"]
-mod lsm6dsm;
+// how the board sits in the airframe, the board's -X axis points towards the nose. For other
+// mountings, e.g. the board's +X axis along the vehicle's +Z axis:
+// `MountingOrientation::axis_aligned(Axis::PosZ, Axis::PosY).unwrap()`
+const MOUNTING_ORIENTATION: MountingOrientation = MountingOrientation::NOSE_NEG_X;
 
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
-    let p = embassy_stm32::init(vlf4_clock());
-    verify_revision(p.ADC1, p.PC4);
+    let board = Board::init();
     info!("Hello world");
 
     // red led
     // high -> led on; low -> led off
-    // change the default feature in Cargo.toml
-    #[cfg(feature = "vlf4r1")]
-    let led = Output::new(p.PB9, Level::Low, Speed::Low);
-    #[cfg(feature = "vlf4r2")]
-    let led = Output::new(p.PD10, Level::Low, Speed::Low);
+    let led = Output::new(board.status_led, Level::Low, Speed::Low);
 
     let fire_signal = singleton!(: Signal::<NoopRawMutex, ()> = Signal::new()).unwrap();
 
-    #[cfg(feature = "vlf4r1")]
-    let cs = p.PA15;
-    #[cfg(feature = "vlf4r2")]
-    let cs = p.PC13;
-    spawner.spawn(
-        imu_task(
-            p.SPI3,
-            p.PC10,
-            p.PC12,
-            p.PC11,
-            cs.into(),
-            p.DMA1_CH4,
-            p.DMA1_CH5,
-            fire_signal,
-        )
-        .unwrap(),
-    );
+    spawner.spawn(imu_task(board.imu, board.imu_int1, fire_signal).unwrap());
 
     spawner.spawn(fire_task(led, fire_signal).unwrap());
 }
 
 #[embassy_executor::task]
 async fn imu_task(
-    spi: Peri<'static, SPI3>,
-    sck: Peri<'static, PC10>,
-    mosi: Peri<'static, PC12>,
-    miso: Peri<'static, PC11>,
-    cs: Peri<'static, AnyPin>,
-    tx_dma: Peri<'static, DMA1_CH4>,
-    rx_dma: Peri<'static, DMA1_CH5>,
+    imu_bus: ImuBus,
+    int1: ImuInterrupt,
     fire_signal: &'static Signal<NoopRawMutex, ()>,
 ) {
     let mut spi_config = SpiConfig::default();
     spi_config.frequency = Hertz(1_000_000);
-    let spi =
-        Mutex::<NoopRawMutex, _>::new(Spi::new(spi, sck, mosi, miso, tx_dma, rx_dma, spi_config));
-    let cs = Output::new(cs, Level::High, Speed::High);
+    let spi = Mutex::<NoopRawMutex, _>::new(Spi::new(
+        imu_bus.spi,
+        imu_bus.sck,
+        imu_bus.mosi,
+        imu_bus.miso,
+        imu_bus.tx_dma,
+        imu_bus.rx_dma,
+        spi_config,
+    ));
+    let cs = Output::new(imu_bus.cs, Level::High, Speed::High);
     let spi_device = SpiDeviceWithConfig::new(&spi, cs, spi_config);
     let mut imu = LSM6DSM::new(spi_device);
-    imu.reset().await.unwrap();
-
-    let mut fired = false;
+    imu.set_mounting_orientation(MOUNTING_ORIENTATION);
+    unwrap!(imu.reset().await);
+
+    // refuse to arm on an IMU that fails its self-test
+    let mut trigger = TiltTrigger::new(45.0);
+    match imu.self_test().await {
+        Ok(report) => {
+            info!("IMU self-test passed: {}", report);
+            trigger.arm();
+        }
+        Err(e) => error!("IMU self-test failed, not arming: {}", e),
+    }
 
-    let sample_rate = 10u64;
-    let mut ticker = Ticker::every(Duration::from_hz(sample_rate));
+    unwrap!(imu.configure_fifo(FifoConfig::default()).await);
+    // INT1 goes high once the FIFO reaches the watermark
+    unwrap!(
+        imu.configure_interrupts(InterruptConfig {
+            int1: InterruptRouting {
+                fifo_watermark: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+        .await
+    );
+    let mut int1 = int1.into_input();
 
-    let angle_low_pass_coeff = Coefficients::<f32>::from_params(
-        Type::LowPass,
-        (sample_rate as f32).hz(),
-        2f32.hz(),
-        Q_BUTTERWORTH_F32,
-    )
-    .unwrap();
-    let mut angle_low_pass = DirectForm2Transposed::new(angle_low_pass_coeff);
+    // fuses gyro and accelerometer, keeps tracking on the gyro alone under thrust
+    let mut attitude = AttitudeEstimator::new(AttitudeEstimatorConfig::default());
+    let mut samples = [IMUData::default(); 64];
     loop {
-        let measurements = imu.read().await.unwrap();
-
-        let acc = Vector3::from_column_slice(&measurements.acc);
-        let down = Vector3::new(-1f32, 0f32, 0f32);
-
-        let angle = acc.angle(&down).to_degrees();
-        let low_passed_angle = angle_low_pass.run(angle);
+        imu.wait_for_data(&mut int1).await;
+        let sample_count = match imu.read_fifo(&mut samples).await {
+            Ok(sample_count) => sample_count,
+            Err(e) => {
+                warn!("IMU read error: {}", e);
+                continue;
+            }
+        };
+
+        for measurements in &samples[..sample_count] {
+            attitude.update(measurements);
+
+            if trigger.update(attitude.tilt_from_vertical()) {
+                fire_signal.signal(());
+            }
+        }
         info!(
-            "angle: {} / {} degrees",
-            (angle * 10.0).round() / 10.0,
-            (low_passed_angle * 10.0).round() / 10.0
+            "tilt: {} degrees, rates: {} {} {} deg/s, gravity reference: {} ({} samples)",
+            (attitude.tilt_from_vertical() * 10.0).round() / 10.0,
+            attitude.roll_rate().round(),
+            attitude.pitch_rate().round(),
+            attitude.yaw_rate().round(),
+            attitude.gravity_reference_valid(),
+            sample_count
         );
-
-        if low_passed_angle > 45.0 && !fired {
-            fire_signal.signal(());
-            fired = true;
-        }
-        ticker.next().await;
     }
 }
 
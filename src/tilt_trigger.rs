@@ -0,0 +1,56 @@
+/// Fires once, the first time the tilt from vertical exceeds the threshold while armed
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq)]
+pub struct TiltTrigger {
+    threshold: f32, // degrees
+    armed: bool,
+    fired: bool,
+}
+
+impl TiltTrigger {
+    /// Starts disarmed
+    pub fn new(threshold: f32) -> Self {
+        Self {
+            threshold,
+            armed: false,
+            fired: false,
+        }
+    }
+
+    pub fn arm(&mut self) {
+        self.armed = true;
+    }
+
+    pub fn is_armed(&self) -> bool {
+        self.armed
+    }
+
+    pub fn has_fired(&self) -> bool {
+        self.fired
+    }
+
+    /// Returns true when the output should fire
+    pub fn update(&mut self, tilt: f32) -> bool {
+        if tilt > self.threshold && self.armed && !self.fired {
+            self.fired = true;
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fires_once_when_armed() {
+        let mut trigger = TiltTrigger::new(45.0);
+        assert!(!trigger.update(60.0));
+
+        trigger.arm();
+        assert!(!trigger.update(30.0));
+        assert!(trigger.update(46.0));
+        assert!(!trigger.update(90.0));
+        assert!(trigger.has_fired());
+    }
+}
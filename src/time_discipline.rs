@@ -0,0 +1,266 @@
+// PPS-disciplined UTC clock. The local oscillator is modelled as an offset and a frequency
+// error against UTC, both re-estimated at every PPS edge. Between edges, and after PPS is
+// lost, UTC is extrapolated with the last frequency error.
+// Local times are ns on the `embassy_time::Instant` timebase.
+
+use core::cell::RefCell;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::Instant;
+
+const NS_PER_SECOND: i64 = 1_000_000_000;
+/// Without an edge for this long the clock is in holdover
+const HOLDOVER_AFTER_NS: i64 = 1_500_000_000;
+/// Largest frequency error accepted for the first interval, ppb
+const MAX_DRIFT_PPB: f64 = 20_000_000.0;
+/// Edges further than this from the predicted time are glitches, ns
+const MAX_EDGE_ERROR_NS: f64 = 100_000.0;
+/// Frequency change allowed across a gap in the edges, ppb
+const MAX_WANDER_PPB: f64 = 1_000.0;
+/// Rejected edges in a row before the frequency is estimated from scratch
+const MAX_REJECTED: u8 = 3;
+/// Weight of a new measurement in the averages
+const GAIN: f64 = 0.2;
+/// Starting point of the jitter estimate, one `Instant` tick
+const INITIAL_JITTER_NS: f64 = 1_000.0;
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncState {
+    /// The last PPS edge was less than 1.5s ago and the frequency error is known
+    Locked,
+    /// Extrapolating without recent edges, or from a single edge
+    Holdover,
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UtcTime {
+    /// ns since the unix epoch
+    pub unix_ns: i64,
+    pub state: SyncState,
+    /// Estimated error, ns
+    pub uncertainty_ns: u64,
+}
+
+#[derive(defmt::Format, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DisciplineStats {
+    pub accepted: u32,
+    /// Edges too far from the predicted time, or not after the previous one
+    pub rejected: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct TimeDiscipline {
+    /// Local time of the last accepted edge and the unix time it marks
+    anchor: Option<(i64, i64)>,
+    /// How much faster the local clock runs than UTC, ppb
+    drift_ppb: Option<f64>,
+    /// Average distance of the edges from their predicted time, ns
+    jitter_ns: f64,
+    /// Average change of the frequency error between edges, ppb
+    wander_ppb: f64,
+    rejected_in_row: u8,
+    stats: DisciplineStats,
+}
+
+impl Default for TimeDiscipline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TimeDiscipline {
+    pub const fn new() -> Self {
+        Self {
+            anchor: None,
+            drift_ppb: None,
+            jitter_ns: INITIAL_JITTER_NS,
+            wander_ppb: 0.0,
+            rejected_in_row: 0,
+            stats: DisciplineStats {
+                accepted: 0,
+                rejected: 0,
+            },
+        }
+    }
+
+    pub fn stats(&self) -> DisciplineStats {
+        self.stats
+    }
+
+    /// Frequency error of the local clock, ppb, positive if it runs fast
+    pub fn drift_ppb(&self) -> Option<f64> {
+        self.drift_ppb
+    }
+
+    /// Feeds a PPS edge captured at local time `edge_ns` that marks the start of
+    /// `unix_time`. Returns false if the edge was rejected.
+    pub fn on_pps(&mut self, edge_ns: i64, unix_time: i64) -> bool {
+        let Some((anchor_ns, anchor_unix_time)) = self.anchor else {
+            self.accept(edge_ns, unix_time);
+            return true;
+        };
+
+        let seconds = unix_time - anchor_unix_time;
+        if seconds <= 0 {
+            return self.reject(edge_ns, unix_time);
+        }
+        let interval = (edge_ns - anchor_ns) as f64;
+        // frequency error measured over this interval
+        let measured_ppb = (interval - (seconds * NS_PER_SECOND) as f64) / seconds as f64;
+
+        match self.drift_ppb {
+            None => {
+                if measured_ppb.abs() > MAX_DRIFT_PPB {
+                    return self.reject(edge_ns, unix_time);
+                }
+                self.drift_ppb = Some(measured_ppb);
+            }
+            Some(drift_ppb) => {
+                let error = (measured_ppb - drift_ppb) * seconds as f64;
+                if error.abs() > MAX_EDGE_ERROR_NS + MAX_WANDER_PPB * seconds as f64 {
+                    return self.reject(edge_ns, unix_time);
+                }
+                self.jitter_ns += GAIN * (error.abs() - self.jitter_ns);
+                self.wander_ppb += GAIN * ((measured_ppb - drift_ppb).abs() - self.wander_ppb);
+                self.drift_ppb = Some(drift_ppb + GAIN * (measured_ppb - drift_ppb));
+            }
+        }
+        self.accept(edge_ns, unix_time);
+        true
+    }
+
+    /// UTC at local time `local_ns`, `None` before the first edge
+    pub fn utc_at(&self, local_ns: i64) -> Option<UtcTime> {
+        let (anchor_ns, anchor_unix_time) = self.anchor?;
+        let elapsed = (local_ns - anchor_ns) as f64;
+        let (drift_ppb, drift_uncertainty_ppb) = match self.drift_ppb {
+            Some(drift_ppb) => (drift_ppb, self.wander_ppb),
+            None => (0.0, MAX_DRIFT_PPB),
+        };
+        let utc_elapsed = elapsed * 1e9 / (1e9 + drift_ppb);
+        let state = if self.drift_ppb.is_some() && elapsed < HOLDOVER_AFTER_NS as f64 {
+            SyncState::Locked
+        } else {
+            SyncState::Holdover
+        };
+        Some(UtcTime {
+            unix_ns: anchor_unix_time * NS_PER_SECOND + utc_elapsed as i64,
+            state,
+            uncertainty_ns: (self.jitter_ns + elapsed.abs() * drift_uncertainty_ppb / 1e9) as u64,
+        })
+    }
+
+    fn accept(&mut self, edge_ns: i64, unix_time: i64) {
+        self.anchor = Some((edge_ns, unix_time));
+        self.rejected_in_row = 0;
+        self.stats.accepted += 1;
+    }
+
+    fn reject(&mut self, edge_ns: i64, unix_time: i64) -> bool {
+        self.stats.rejected += 1;
+        self.rejected_in_row += 1;
+        // the glitch is more likely the old estimate, start over from this edge
+        if self.rejected_in_row >= MAX_REJECTED {
+            self.drift_ppb = None;
+            self.jitter_ns = INITIAL_JITTER_NS;
+            self.wander_ppb = 0.0;
+            self.anchor = Some((edge_ns, unix_time));
+            self.rejected_in_row = 0;
+        }
+        false
+    }
+}
+
+/// `TimeDiscipline` shared between the PPS task and everything that needs UTC
+pub struct SharedTimeDiscipline(Mutex<CriticalSectionRawMutex, RefCell<TimeDiscipline>>);
+
+impl SharedTimeDiscipline {
+    pub const fn new() -> Self {
+        Self(Mutex::new(RefCell::new(TimeDiscipline::new())))
+    }
+
+    pub fn on_pps(&self, edge_ns: i64, unix_time: i64) -> bool {
+        self.0
+            .lock(|discipline| discipline.borrow_mut().on_pps(edge_ns, unix_time))
+    }
+
+    /// UTC now, `None` before the first PPS edge
+    pub fn now_utc(&self) -> Option<UtcTime> {
+        self.utc_at(instant_ns(Instant::now()))
+    }
+
+    pub fn utc_at(&self, local_ns: i64) -> Option<UtcTime> {
+        self.0
+            .lock(|discipline| discipline.borrow().utc_at(local_ns))
+    }
+
+    pub fn stats(&self) -> DisciplineStats {
+        self.0.lock(|discipline| discipline.borrow().stats())
+    }
+}
+
+impl Default for SharedTimeDiscipline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Local time of an `Instant`, ns
+pub fn instant_ns(instant: Instant) -> i64 {
+    instant.as_micros() as i64 * 1_000
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // local clock 50ppm fast, edges at unix time 1000 + k with ±300ns of jitter
+    const DRIFT_PPB: i64 = 50_000;
+    fn edge_ns(k: i64) -> i64 {
+        let jitter = [0, 300, -200, 100, -300][k as usize % 5];
+        5_000_000 + k * (NS_PER_SECOND + DRIFT_PPB) + jitter
+    }
+
+    #[test]
+    fn tracks_drift_and_holds_over() {
+        let mut discipline = TimeDiscipline::new();
+        assert_eq!(discipline.utc_at(0), None);
+        for k in 0..20 {
+            assert!(discipline.on_pps(edge_ns(k), 1000 + k));
+        }
+        assert!((discipline.drift_ppb().unwrap() - DRIFT_PPB as f64).abs() < 500.0);
+
+        // half way to the next edge
+        let utc = discipline.utc_at(edge_ns(19) + 500_025_000).unwrap();
+        assert_eq!(utc.state, SyncState::Locked);
+        assert!((utc.unix_ns - 1_019_500_000_000).abs() < 1_000);
+
+        // PPS lost for a minute
+        let utc = discipline.utc_at(edge_ns(79)).unwrap();
+        assert_eq!(utc.state, SyncState::Holdover);
+        let error = (utc.unix_ns - 1079 * NS_PER_SECOND).abs();
+        assert!(error < 20_000, "error {error}");
+        assert!(utc.uncertainty_ns > 0);
+    }
+
+    #[test]
+    fn rejects_glitches() {
+        let mut discipline = TimeDiscipline::new();
+        for k in 0..5 {
+            discipline.on_pps(edge_ns(k), 1000 + k);
+        }
+        // noise on the PPS line and a repeated second
+        assert!(!discipline.on_pps(edge_ns(5) - 300_000_000, 1005));
+        assert!(!discipline.on_pps(edge_ns(4) + 1_000, 1004));
+        assert!(discipline.on_pps(edge_ns(5), 1005));
+        // an edge after a gap is fine
+        assert!(discipline.on_pps(edge_ns(9), 1009));
+        assert_eq!(
+            discipline.stats(),
+            DisciplineStats {
+                accepted: 7,
+                rejected: 2,
+            }
+        );
+    }
+}
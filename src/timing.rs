@@ -0,0 +1,12 @@
+use chrono::{TimeZone as _, Utc};
+use nmea::Nmea;
+use nmea::sentences::FixType;
+
+/// UTC time of the last fix as a unix timestamp, `None` without a valid fix
+pub fn fix_unix_time(nmea: &Nmea) -> Option<i64> {
+    if matches!(nmea.fix_type, None | Some(FixType::Invalid)) {
+        return None;
+    }
+    let datetime = nmea.fix_date?.and_time(nmea.fix_time?);
+    Some(Utc.from_utc_datetime(&datetime).timestamp())
+}
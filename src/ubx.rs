@@ -0,0 +1,520 @@
+// u-blox UBX protocol. Frames are `0xB5 0x62 class id length(u16 LE) payload ck_a ck_b`,
+// the checksum is an 8 bit Fletcher over class, id, length and payload.
+// Message layouts follow the u-blox 8 / M8 receiver description.
+
+use chrono::{NaiveDate, TimeZone as _, Utc};
+use heapless::Vec;
+
+pub const SYNC_1: u8 = 0xB5;
+pub const SYNC_2: u8 = 0x62;
+/// Largest payload the decoder accepts, enough for the navigation and config messages
+pub const MAX_PAYLOAD: usize = 256;
+
+pub const CLASS_NAV: u8 = 0x01;
+pub const CLASS_ACK: u8 = 0x05;
+pub const CLASS_CFG: u8 = 0x06;
+pub const CLASS_TIM: u8 = 0x0D;
+/// Standard NMEA sentences, for CFG-MSG
+pub const CLASS_NMEA: u8 = 0xF0;
+
+pub const ID_NAV_PVT: u8 = 0x07;
+pub const ID_NAV_TIMEUTC: u8 = 0x21;
+pub const ID_ACK_NAK: u8 = 0x00;
+pub const ID_ACK_ACK: u8 = 0x01;
+pub const ID_TIM_TP: u8 = 0x01;
+pub const ID_CFG_PRT: u8 = 0x00;
+pub const ID_CFG_MSG: u8 = 0x01;
+pub const ID_CFG_RATE: u8 = 0x08;
+pub const ID_CFG_NAV5: u8 = 0x24;
+pub const ID_NMEA_GGA: u8 = 0x00;
+pub const ID_NMEA_GLL: u8 = 0x01;
+pub const ID_NMEA_GSA: u8 = 0x02;
+pub const ID_NMEA_GSV: u8 = 0x03;
+pub const ID_NMEA_RMC: u8 = 0x04;
+pub const ID_NMEA_VTG: u8 = 0x05;
+
+// seconds between the unix epoch and the GPS epoch (1980-01-06)
+const GPS_EPOCH_UNIX: i64 = 315_964_800;
+const SECONDS_PER_WEEK: i64 = 604_800;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UbxFrame<'a> {
+    pub class: u8,
+    pub id: u8,
+    pub payload: &'a [u8],
+}
+
+impl<'a> UbxFrame<'a> {
+    pub const fn new(class: u8, id: u8, payload: &'a [u8]) -> Self {
+        Self { class, id, payload }
+    }
+
+    /// Length of the frame including sync bytes and checksum
+    pub const fn encoded_len(&self) -> usize {
+        self.payload.len() + 8
+    }
+
+    /// Writes the frame to the start of `buffer`, `None` if it doesn't fit
+    pub fn encode<'b>(&self, buffer: &'b mut [u8]) -> Option<&'b [u8]> {
+        let frame = buffer.get_mut(..self.encoded_len())?;
+        let length = (self.payload.len() as u16).to_le_bytes();
+        frame[..6].copy_from_slice(&[SYNC_1, SYNC_2, self.class, self.id, length[0], length[1]]);
+        frame[6..6 + self.payload.len()].copy_from_slice(self.payload);
+        let (ck_a, ck_b) = ubx_checksum(&frame[2..6 + self.payload.len()]);
+        frame[6 + self.payload.len()] = ck_a;
+        frame[7 + self.payload.len()] = ck_b;
+        Some(frame)
+    }
+}
+
+/// 8 bit Fletcher checksum, returns (CK_A, CK_B)
+pub fn ubx_checksum(bytes: &[u8]) -> (u8, u8) {
+    bytes.iter().fold((0u8, 0u8), |(a, b), &byte| {
+        let a = a.wrapping_add(byte);
+        (a, b.wrapping_add(a))
+    })
+}
+
+#[derive(defmt::Format, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct UbxStats {
+    /// Frames with a valid checksum
+    pub frames: u32,
+    pub checksum_errors: u32,
+    /// Frames with a payload longer than `MAX_PAYLOAD`
+    pub overflow_errors: u32,
+}
+
+impl UbxStats {
+    /// Frames dropped for any reason
+    pub fn errors(&self) -> u32 {
+        self.checksum_errors + self.overflow_errors
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum State {
+    #[default]
+    Sync1,
+    Sync2,
+    Frame,
+}
+
+/// Splits a byte stream into UBX frames, everything outside of a frame is ignored
+#[derive(Debug, Default)]
+pub struct UbxDecoder {
+    // class, id, length, payload and checksum
+    buffer: Vec<u8, { MAX_PAYLOAD + 6 }>,
+    state: State,
+    stats: UbxStats,
+}
+
+impl UbxDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn stats(&self) -> UbxStats {
+        self.stats
+    }
+
+    /// Whether the sync bytes have been received and the rest of a frame is expected
+    pub fn in_frame(&self) -> bool {
+        self.state == State::Frame
+    }
+
+    /// Returns the frame once its last byte has been pushed and the checksum matches
+    pub fn push(&mut self, byte: u8) -> Option<UbxFrame<'_>> {
+        match self.state {
+            State::Sync1 => {
+                if byte == SYNC_1 {
+                    self.state = State::Sync2;
+                }
+            }
+            State::Sync2 => {
+                self.state = match byte {
+                    SYNC_2 => {
+                        self.buffer.clear();
+                        State::Frame
+                    }
+                    SYNC_1 => State::Sync2,
+                    _ => State::Sync1,
+                };
+            }
+            State::Frame => {
+                // the length is checked against the capacity once the header is complete
+                self.buffer.push(byte).ok();
+                if self.buffer.len() < 4 {
+                    return None;
+                }
+                let payload_len = u16::from_le_bytes([self.buffer[2], self.buffer[3]]) as usize;
+                if payload_len > MAX_PAYLOAD {
+                    self.stats.overflow_errors += 1;
+                    self.state = State::Sync1;
+                } else if self.buffer.len() == payload_len + 6 {
+                    self.state = State::Sync1;
+                    let (ck_a, ck_b) = ubx_checksum(&self.buffer[..payload_len + 4]);
+                    if (ck_a, ck_b) != (self.buffer[payload_len + 4], self.buffer[payload_len + 5])
+                    {
+                        self.stats.checksum_errors += 1;
+                        return None;
+                    }
+                    self.stats.frames += 1;
+                    return Some(self.last_frame());
+                }
+            }
+        }
+        None
+    }
+
+    /// The frame returned by the last `push`
+    pub(crate) fn last_frame(&self) -> UbxFrame<'_> {
+        let payload_len = self.buffer.len() - 6;
+        UbxFrame::new(
+            self.buffer[0],
+            self.buffer[1],
+            &self.buffer[4..payload_len + 4],
+        )
+    }
+}
+
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UbxMessage {
+    NavPvt(NavPvt),
+    NavTimeUtc(NavTimeUtc),
+    TimTp(TimTp),
+    /// The receiver accepted the message with this class and id
+    Ack {
+        class: u8,
+        id: u8,
+    },
+    /// The receiver rejected the message with this class and id
+    Nak {
+        class: u8,
+        id: u8,
+    },
+}
+
+impl UbxMessage {
+    /// `None` for messages that aren't decoded or have an unexpected length
+    pub fn parse(frame: &UbxFrame) -> Option<Self> {
+        let p = frame.payload;
+        Some(match (frame.class, frame.id) {
+            (CLASS_NAV, ID_NAV_PVT) => Self::NavPvt(NavPvt::parse(p)?),
+            (CLASS_NAV, ID_NAV_TIMEUTC) => Self::NavTimeUtc(NavTimeUtc::parse(p)?),
+            (CLASS_TIM, ID_TIM_TP) => Self::TimTp(TimTp::parse(p)?),
+            (CLASS_ACK, ID_ACK_ACK) if p.len() == 2 => Self::Ack {
+                class: p[0],
+                id: p[1],
+            },
+            (CLASS_ACK, ID_ACK_NAK) if p.len() == 2 => Self::Nak {
+                class: p[0],
+                id: p[1],
+            },
+            _ => return None,
+        })
+    }
+}
+
+/// UBX-NAV-PVT navigation solution, fields are in the units of the protocol
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NavPvt {
+    /// GPS time of week, ms
+    pub itow: u32,
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub min: u8,
+    pub sec: u8,
+    /// Bit 0 valid date, bit 1 valid time, bit 2 fully resolved
+    pub valid: u8,
+    /// Time accuracy estimate, ns
+    pub time_accuracy: u32,
+    /// Fraction of the second, ns, can be negative
+    pub nano: i32,
+    /// 0 no fix, 1 dead reckoning, 2 2D, 3 3D, 4 GNSS + dead reckoning, 5 time only
+    pub fix_type: u8,
+    /// Bit 0 gnssFixOK, bit 1 differential corrections, bits 6-7 carrier phase solution
+    pub flags: u8,
+    pub num_sv: u8,
+    /// 1e-7 deg
+    pub lon: i32,
+    /// 1e-7 deg
+    pub lat: i32,
+    /// Height above the ellipsoid, mm
+    pub height: i32,
+    /// Height above mean sea level, mm
+    pub height_msl: i32,
+    /// Horizontal accuracy estimate, mm
+    pub horizontal_accuracy: u32,
+    /// Vertical accuracy estimate, mm
+    pub vertical_accuracy: u32,
+    /// NED velocity, mm/s
+    pub vel_n: i32,
+    pub vel_e: i32,
+    pub vel_d: i32,
+    /// Ground speed, mm/s
+    pub ground_speed: i32,
+    /// Heading of motion, 1e-5 deg
+    pub heading: i32,
+    /// Speed accuracy estimate, mm/s
+    pub speed_accuracy: u32,
+    /// Heading accuracy estimate, 1e-5 deg
+    pub heading_accuracy: u32,
+    /// 0.01
+    pub pdop: u16,
+}
+
+impl NavPvt {
+    pub const LEN: usize = 92;
+
+    pub fn parse(p: &[u8]) -> Option<Self> {
+        if p.len() != Self::LEN {
+            return None;
+        }
+        Some(Self {
+            itow: u32_at(p, 0),
+            year: u16_at(p, 4),
+            month: p[6],
+            day: p[7],
+            hour: p[8],
+            min: p[9],
+            sec: p[10],
+            valid: p[11],
+            time_accuracy: u32_at(p, 12),
+            nano: i32_at(p, 16),
+            fix_type: p[20],
+            flags: p[21],
+            num_sv: p[23],
+            lon: i32_at(p, 24),
+            lat: i32_at(p, 28),
+            height: i32_at(p, 32),
+            height_msl: i32_at(p, 36),
+            horizontal_accuracy: u32_at(p, 40),
+            vertical_accuracy: u32_at(p, 44),
+            vel_n: i32_at(p, 48),
+            vel_e: i32_at(p, 52),
+            vel_d: i32_at(p, 56),
+            ground_speed: i32_at(p, 60),
+            heading: i32_at(p, 64),
+            speed_accuracy: u32_at(p, 68),
+            heading_accuracy: u32_at(p, 72),
+            pdop: u16_at(p, 76),
+        })
+    }
+
+    pub fn fix_ok(&self) -> bool {
+        self.flags & 0x01 != 0
+    }
+
+    pub fn differential(&self) -> bool {
+        self.flags & 0x02 != 0
+    }
+
+    /// 0 none, 1 float ambiguities, 2 fixed ambiguities
+    pub fn carrier_solution(&self) -> u8 {
+        self.flags >> 6
+    }
+
+    /// UTC time rounded down to the second, `None` unless date and time are valid
+    pub fn unix_time(&self) -> Option<i64> {
+        if self.valid & 0x03 != 0x03 {
+            return None;
+        }
+        unix_time(
+            self.year, self.month, self.day, self.hour, self.min, self.sec,
+        )
+    }
+}
+
+/// UBX-NAV-TIMEUTC
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NavTimeUtc {
+    /// GPS time of week, ms
+    pub itow: u32,
+    /// Time accuracy estimate, ns
+    pub time_accuracy: u32,
+    /// Fraction of the second, ns, can be negative
+    pub nano: i32,
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub min: u8,
+    pub sec: u8,
+    /// Bit 0 valid time of week, bit 1 valid week number, bit 2 valid UTC
+    pub valid: u8,
+}
+
+impl NavTimeUtc {
+    pub const LEN: usize = 20;
+
+    pub fn parse(p: &[u8]) -> Option<Self> {
+        if p.len() != Self::LEN {
+            return None;
+        }
+        Some(Self {
+            itow: u32_at(p, 0),
+            time_accuracy: u32_at(p, 4),
+            nano: i32_at(p, 8),
+            year: u16_at(p, 12),
+            month: p[14],
+            day: p[15],
+            hour: p[16],
+            min: p[17],
+            sec: p[18],
+            valid: p[19],
+        })
+    }
+
+    /// UTC time rounded down to the second, `None` unless UTC is valid
+    pub fn unix_time(&self) -> Option<i64> {
+        if self.valid & 0x04 == 0 {
+            return None;
+        }
+        unix_time(
+            self.year, self.month, self.day, self.hour, self.min, self.sec,
+        )
+    }
+}
+
+/// UBX-TIM-TP, sent before the time pulse it describes
+#[derive(defmt::Format, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimTp {
+    /// Time of week of the next pulse, ms
+    pub tow_ms: u32,
+    /// Sub-millisecond part of `tow_ms`, ms * 2^-32
+    pub tow_sub_ms: u32,
+    /// Quantization error of the pulse, ps
+    pub quantization_error: i32,
+    pub week: u16,
+    /// Bit 0 time base is UTC (otherwise GNSS), bit 1 UTC available
+    pub flags: u8,
+    pub ref_info: u8,
+}
+
+impl TimTp {
+    pub const LEN: usize = 16;
+
+    pub fn parse(p: &[u8]) -> Option<Self> {
+        if p.len() != Self::LEN {
+            return None;
+        }
+        Some(Self {
+            tow_ms: u32_at(p, 0),
+            tow_sub_ms: u32_at(p, 4),
+            quantization_error: i32_at(p, 8),
+            week: u16_at(p, 12),
+            flags: p[14],
+            ref_info: p[15],
+        })
+    }
+
+    /// UTC time of the next pulse in ns since the unix epoch, `None` unless the time base is
+    /// UTC
+    pub fn pulse_unix_time_ns(&self) -> Option<i64> {
+        if self.flags & 0x03 != 0x03 {
+            return None;
+        }
+        let seconds = GPS_EPOCH_UNIX + self.week as i64 * SECONDS_PER_WEEK;
+        let sub_ms_ns = (self.tow_sub_ms as i64 * 1_000_000) >> 32;
+        Some(seconds * 1_000_000_000 + self.tow_ms as i64 * 1_000_000 + sub_ms_ns)
+    }
+}
+
+fn unix_time(year: u16, month: u8, day: u8, hour: u8, min: u8, sec: u8) -> Option<i64> {
+    let datetime = NaiveDate::from_ymd_opt(year as i32, month as u32, day as u32)?.and_hms_opt(
+        hour as u32,
+        min as u32,
+        sec as u32,
+    )?;
+    Some(Utc.from_utc_datetime(&datetime).timestamp())
+}
+
+fn u16_at(p: &[u8], i: usize) -> u16 {
+    u16::from_le_bytes([p[i], p[i + 1]])
+}
+
+fn u32_at(p: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes([p[i], p[i + 1], p[i + 2], p[i + 3]])
+}
+
+fn i32_at(p: &[u8], i: usize) -> i32 {
+    u32_at(p, i) as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_and_decodes_frames() {
+        let mut buffer = [0; 16];
+        let ack = UbxFrame::new(CLASS_ACK, ID_ACK_ACK, &[CLASS_CFG, 0x00])
+            .encode(&mut buffer)
+            .unwrap();
+        assert_eq!(
+            ack,
+            [0xB5, 0x62, 0x05, 0x01, 0x02, 0x00, 0x06, 0x00, 0x0E, 0x37]
+        );
+
+        let mut stream = std::vec::Vec::from(b"\xB5\xB5$GP");
+        stream.extend_from_slice(ack);
+        // corrupted copy, then a payload that doesn't fit
+        stream.extend_from_slice(&ack[..9]);
+        stream.extend_from_slice(&[0x38, 0xB5, 0x62, 0x01, 0x07, 0xFF, 0xFF]);
+        stream.extend_from_slice(ack);
+
+        let mut decoder = UbxDecoder::new();
+        let mut messages = std::vec::Vec::new();
+        for &byte in &stream {
+            if let Some(frame) = decoder.push(byte) {
+                messages.push(UbxMessage::parse(&frame).unwrap());
+            }
+        }
+        let expected = UbxMessage::Ack {
+            class: CLASS_CFG,
+            id: 0x00,
+        };
+        assert_eq!(messages, [expected, expected]);
+        assert_eq!(
+            decoder.stats(),
+            UbxStats {
+                frames: 2,
+                checksum_errors: 1,
+                overflow_errors: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn parses_timing_messages() {
+        let mut pvt = [0u8; NavPvt::LEN];
+        pvt[4..6].copy_from_slice(&2024u16.to_le_bytes());
+        pvt[6..11].copy_from_slice(&[6, 15, 12, 30, 5]);
+        pvt[11] = 0x07;
+        pvt[20] = 3;
+        pvt[21] = 0x01;
+        pvt[28..32].copy_from_slice(&432_601_234i32.to_le_bytes());
+        pvt[36..40].copy_from_slice(&(-1_500i32).to_le_bytes());
+        let pvt = NavPvt::parse(&pvt).unwrap();
+        assert!(pvt.fix_ok());
+        assert_eq!(pvt.lat, 432_601_234);
+        assert_eq!(pvt.height_msl, -1_500);
+        assert_eq!(pvt.unix_time(), Some(1_718_454_605));
+        assert_eq!(NavPvt::parse(&[0; 91]), None);
+
+        // week 2318, 1 s into the week, plus half a millisecond
+        let mut tp = [0u8; TimTp::LEN];
+        tp[0..4].copy_from_slice(&1_000u32.to_le_bytes());
+        tp[4..8].copy_from_slice(&(1u32 << 31).to_le_bytes());
+        tp[12..14].copy_from_slice(&2318u16.to_le_bytes());
+        tp[14] = 0x03;
+        let tp = TimTp::parse(&tp).unwrap();
+        let expected_s = GPS_EPOCH_UNIX + 2318 * SECONDS_PER_WEEK + 1;
+        assert_eq!(
+            tp.pulse_unix_time_ns(),
+            Some(expected_s * 1_000_000_000 + 500_000)
+        );
+    }
+}